@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A byte budget shared by the reassembly buffers of every TCP flow.
+pub(crate) struct Arena {
+    capacity: usize,
+    used: AtomicUsize,
+}
+
+impl Arena {
+    pub(crate) fn new(capacity: usize) -> Arena {
+        Arena {
+            capacity,
+            used: AtomicUsize::new(0),
+        }
+    }
+    // Takes `len` bytes of the budget, or nothing if they don't all fit.
+    pub(crate) fn reserve(&self, len: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(len).filter(|&used| used <= self.capacity)
+            })
+            .is_ok()
+    }
+    pub(crate) fn release(&self, len: usize) {
+        self.used.fetch_sub(len, Ordering::AcqRel);
+    }
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+    pub(crate) fn available(&self) -> usize {
+        self.capacity.saturating_sub(self.used())
+    }
+}
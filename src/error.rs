@@ -8,12 +8,20 @@ pub enum IpStackError {
     UnsupportedTransportProtocol,
     #[error("The packet is invalid")]
     InvalidPacket,
+    #[error("The packet is truncated")]
+    TruncatedPacket,
+    #[error("The frame exceeds read_mtu")]
+    OversizedFrame,
     #[error("Write error: {0}")]
     PacketWriteError(WriteError),
     #[error("Invalid Tcp packet")]
     InvalidTcpPacket,
+    #[error("The TCP data offset is out of bounds")]
+    InvalidTcpDataOffset,
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Accept Error")]
     AcceptError,
+    #[error("Accepting streams is disabled by IpStackConfig::disable_accept")]
+    AcceptDisabled,
 }
@@ -0,0 +1,47 @@
+use crate::packet::{NetworkPacket, NetworkTuple};
+
+/// Why a flow was removed from the stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CloseReason {
+    /// The stream was dropped while the flow was still open.
+    Dropped,
+    /// The flow saw no traffic for its timeout.
+    IdleTimeout,
+    /// The peer closed the connection with a FIN.
+    PeerFin,
+    /// The stream was shut down and the FIN exchange completed.
+    LocalFin,
+    /// The peer reset the connection.
+    PeerReset,
+    /// The deadline set on the stream passed.
+    Deadline,
+    /// The stack itself was shut down, or its device closed.
+    Shutdown,
+    /// Every flow was dropped by [`crate::IpStack::reset_all`].
+    Reset,
+    /// The flow transferred its [`crate::IpStackConfig::flow_quota`].
+    QuotaExceeded,
+}
+
+impl CloseReason {
+    // Streams tag the internal removal packet with the reason as its payload.
+    pub(crate) fn from_marker(packet: &NetworkPacket) -> CloseReason {
+        match packet.payload.first() {
+            Some(1) => CloseReason::IdleTimeout,
+            Some(2) => CloseReason::PeerFin,
+            Some(3) => CloseReason::LocalFin,
+            Some(4) => CloseReason::PeerReset,
+            Some(5) => CloseReason::Deadline,
+            Some(6) => CloseReason::Shutdown,
+            Some(8) => CloseReason::QuotaExceeded,
+            _ => CloseReason::Dropped,
+        }
+    }
+}
+
+/// Flow lifecycle notifications, see [`crate::IpStack::flow_events`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowEvent {
+    Closed(NetworkTuple, CloseReason),
+}
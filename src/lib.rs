@@ -1,30 +1,58 @@
+use bytes::{BufMut, BytesMut};
 pub use error::IpStackError;
-use packet::{NetworkPacket, NetworkTuple};
+use etherparse::{IpHeader, TransportHeader};
+pub use event::{CloseReason, FlowEvent};
+use packet::NetworkPacket;
+pub use packet::NetworkTuple;
 use std::{
+    cmp,
     collections::{
         hash_map::Entry::{Occupied, Vacant},
         HashMap,
     },
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
-use stream::IpStackStream;
+use stream::{
+    ClosePolicy, FlowShared, IpStackStream, ReservedBitsPolicy, TcpState, TinygramPolicy,
+    UdpDirection, UdpOverflowPolicy, UdpPayloadTransform,
+};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     select,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
 };
-use tracing::{error, trace};
+use tracing::{error, trace, warn, Level};
 
 use crate::{
+    arena::Arena,
     packet::IpStackPacketProtocol,
     stream::{IpStackTcpStream, IpStackUdpStream},
 };
+mod arena;
 mod error;
+mod event;
 mod packet;
 pub mod stream;
 
 const DROP_TTL: u8 = 0;
 
+// Shortest period between checks for streams that were accepted but never
+// polled.
+const MIN_REAP_PERIOD: Duration = Duration::from_millis(10);
+
+// Shortest period between snapshots sent by IpStack::emit_stats.
+const MIN_STATS_PERIOD: Duration = Duration::from_millis(10);
+
 #[cfg(not(target_os = "windows"))]
 const TTL: u8 = 64;
 
@@ -44,20 +72,176 @@ const TUN_PROTO_IP6: [u8; 2] = [0x00, 0x02];
 #[cfg(target_os = "macos")]
 const TUN_PROTO_IP4: [u8; 2] = [0x00, 0x02];
 
+pub type FlowCallback = Arc<dyn Fn(NetworkTuple) + Send + Sync>;
+pub type FlowRemovedCallback = Arc<dyn Fn(NetworkTuple, CloseReason) + Send + Sync>;
+pub type AddrFilter = Arc<dyn Fn(IpAddr) -> bool + Send + Sync>;
+pub type FrameFn = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+pub type DeframeFn = Arc<dyn Fn(&[u8]) -> Option<(usize, usize)> + Send + Sync>;
+pub type StallCallback = Arc<dyn Fn(Duration) + Send + Sync>;
+pub type FlowLogLevel = Arc<dyn Fn(NetworkTuple) -> Level + Send + Sync>;
+pub type StateChangeCallback = Arc<dyn Fn(NetworkTuple, TcpState, TcpState) + Send + Sync>;
+
 pub struct IpStackConfig {
+    /// Egress MTU, used to segment outgoing packets.
     pub mtu: u16,
+    /// Ingress MTU, used to size the device read buffer. Larger frames are
+    /// dropped and counted, see [`IpStack::oversized_frames`].
+    pub read_mtu: usize,
     pub packet_info: bool,
+    /// Handshake timeout. Accepted streams never polled within it are reset.
     pub tcp_timeout: Duration,
+    /// Idle timeout. Accepted streams never polled within it are dropped.
     pub udp_timeout: Duration,
+    /// Unacknowledged bytes at which TCP writes stop being accepted.
+    pub send_buffer_high_watermark: u32,
+    /// Unacknowledged bytes below which a blocked TCP writer is resumed.
+    pub send_buffer_low_watermark: u32,
+    /// Maximum number of out-of-order segments buffered per TCP flow.
+    pub max_out_of_order_segments: usize,
+    /// Applied to UDP payloads of flows destined to `dns_port`, in both directions.
+    pub dns_transform: Option<UdpPayloadTransform>,
+    pub dns_port: u16,
+    /// Send a RST to the peer of every established TCP flow on shutdown.
+    pub shutdown_rst: bool,
+    /// How a TCP flow is closed once the deadline set on its stream passes.
+    pub deadline_policy: ClosePolicy,
+    /// Called instead of delivering packets that arrive with a TTL (or hop
+    /// limit) of 0. By default they are delivered like any other packet.
+    pub on_ttl_zero: Option<FlowCallback>,
+    /// Destinations a new flow may be created for; packets opening a flow to
+    /// any other address are dropped. Without a filter every address is served.
+    pub local_addr_filter: Option<AddrFilter>,
+    /// Window scale offered in SYN-ACKs to peers that offer one themselves.
+    /// Without it, window scaling is never negotiated.
+    pub window_scale: Option<u8>,
+    /// Wraps every IP packet written to the device, replacing `packet_info`.
+    pub frame: Option<FrameFn>,
+    /// Returns the bounds of the IP packet within each device read, replacing
+    /// `packet_info`. Reads it returns `None` for are dropped. `read_mtu` must
+    /// leave room for the framing.
+    pub deframe: Option<DeframeFn>,
+    /// Maximum number of TCP flows; SYNs past it are answered with a RST.
+    pub max_tcp_flows: usize,
+    /// Maximum number of UDP flows; see `udp_overflow_policy`.
+    pub max_udp_flows: usize,
+    pub udp_overflow_policy: UdpOverflowPolicy,
+    /// Maximum number of segments a single TCP write enqueues.
+    pub max_write_segments: usize,
+    /// Pure ACKs are collected for up to this long and written to the device
+    /// together in one write. Other packets are never delayed. Off unless
+    /// set, see [`IpStackConfig::ack_batch_window`].
+    pub ack_batch_window: Option<Duration>,
+    /// Negotiates TCP timestamps with peers that offer them.
+    pub tcp_timestamps: bool,
+    /// Packets queued for a flow before its stream is first polled. Past
+    /// this, packets are dropped until the application starts reading; 0
+    /// drops all of them.
+    pub pre_accept_buffer: usize,
+    /// Starts each flow's IPv4 identification and TCP timestamp clock at a
+    /// random value, so flows can't be correlated through them.
+    pub randomize_flow_ids: bool,
+    /// Called from the stack's loop as each flow is removed, before the
+    /// matching [`FlowEvent`] is sent.
+    pub on_flow_removed: Option<FlowRemovedCallback>,
+    /// Bytes all TCP flows may hold for reassembly together. As it runs out,
+    /// windows shrink, and once it's spent SYNs are answered with a RST.
+    pub buffer_limit: usize,
+    /// Time a TCP writer waits on a peer advertising a zero window before
+    /// probing it. Each probe doubles it, up to `persist_max_interval`.
+    pub persist_interval: Duration,
+    pub persist_max_interval: Duration,
+    /// Reads the device into a reusable `BytesMut` through
+    /// [`AsyncReadExt::read_buf`] instead of a zeroed fixed buffer, for
+    /// devices that read straight into uninitialized memory.
+    pub bytes_ingress: bool,
+    /// Payload bytes a TCP flow may read and write in total. Writes are cut
+    /// short at it, and once it's reached the flow is closed as
+    /// `quota_policy` says. After a graceful close, new data from the peer
+    /// is answered with a RST.
+    pub flow_quota: Option<u64>,
+    pub quota_policy: ClosePolicy,
+    /// Up to this much is added at random to each TCP timer as it's armed, so
+    /// the timers of many flows don't fire in lockstep. It applies to the
+    /// retransmission and persist timers.
+    pub timer_jitter: Duration,
+    /// Diagnostic mode: every packet read is written straight back with its
+    /// source and destination swapped, and no flows are ever created.
+    pub loopback: bool,
+    pub reserved_bits_policy: ReservedBitsPolicy,
+    /// TCP retransmission timeout before any backoff. The earliest
+    /// unacknowledged segment is resent each time it passes.
+    pub initial_rto: Duration,
+    /// Window advertised in SYN-ACKs, never scaled. Without it, and once the
+    /// handshake completes, the window is the free space in the read buffer.
+    pub initial_recv_window: Option<u16>,
+    /// Called, after a warning is logged, each time this long passes without
+    /// a read from the device, with the time since the last one. Egress keeps
+    /// going while reads are stuck.
+    pub read_watchdog: Option<(Duration, StallCallback)>,
+    /// TCP segments with less data than this are tinygrams, handled as
+    /// `tinygram_policy` says. 0 treats none of them so.
+    pub min_segment_size: usize,
+    pub tinygram_policy: TinygramPolicy,
+    /// Chooses the log level of each new TCP flow from its tuple, see
+    /// [`IpStackTcpStream::set_log_level`]. Without it every flow logs at `INFO`.
+    pub flow_log_level: Option<FlowLogLevel>,
+    /// No streams are ever produced: packets that would open a flow are
+    /// refused as past `max_tcp_flows` and `max_udp_flows`, and
+    /// [`IpStack::accept`] fails at once. Meant for `loopback` and other
+    /// deployments that never accept, which then don't set up the channel.
+    pub disable_accept: bool,
+    /// Called with the old state and then the new one each time a TCP flow's
+    /// state changes, on whichever task is polling its stream. Sending the
+    /// SYN-ACK within `SynReceived` doesn't count as a change.
+    pub on_state_change: Option<StateChangeCallback>,
 }
 
 impl Default for IpStackConfig {
     fn default() -> Self {
         IpStackConfig {
             mtu: u16::MAX,
+            read_mtu: u16::MAX as usize,
             packet_info: false,
             tcp_timeout: Duration::from_secs(60),
             udp_timeout: Duration::from_secs(30),
+            send_buffer_high_watermark: 1024 * 16, // 16KB
+            send_buffer_low_watermark: 1024 * 8,   // 8KB
+            max_out_of_order_segments: 128,
+            dns_transform: None,
+            dns_port: 53,
+            shutdown_rst: false,
+            deadline_policy: ClosePolicy::Graceful,
+            on_ttl_zero: None,
+            local_addr_filter: None,
+            window_scale: None,
+            frame: None,
+            deframe: None,
+            max_tcp_flows: usize::MAX,
+            max_udp_flows: usize::MAX,
+            udp_overflow_policy: UdpOverflowPolicy::Drop,
+            max_write_segments: 16,
+            ack_batch_window: None,
+            tcp_timestamps: false,
+            pre_accept_buffer: 64,
+            randomize_flow_ids: false,
+            on_flow_removed: None,
+            buffer_limit: usize::MAX,
+            persist_interval: Duration::from_secs(1),
+            persist_max_interval: Duration::from_secs(60),
+            bytes_ingress: false,
+            flow_quota: None,
+            quota_policy: ClosePolicy::Graceful,
+            timer_jitter: Duration::ZERO,
+            loopback: false,
+            reserved_bits_policy: ReservedBitsPolicy::Pass,
+            initial_rto: Duration::from_secs(1),
+            initial_recv_window: None,
+            read_watchdog: None,
+            min_segment_size: 0,
+            tinygram_policy: TinygramPolicy::Coalesce,
+            flow_log_level: None,
+            disable_accept: false,
+            on_state_change: None,
         }
     }
 }
@@ -72,41 +256,702 @@ impl IpStackConfig {
     pub fn mtu(&mut self, mtu: u16) {
         self.mtu = mtu;
     }
+    pub fn read_mtu(&mut self, read_mtu: usize) {
+        self.read_mtu = read_mtu;
+    }
     pub fn packet_info(&mut self, packet_info: bool) {
         self.packet_info = packet_info;
     }
+    pub fn send_buffer_high_watermark(&mut self, watermark: u32) {
+        self.send_buffer_high_watermark = watermark;
+    }
+    pub fn send_buffer_low_watermark(&mut self, watermark: u32) {
+        self.send_buffer_low_watermark = watermark;
+    }
+    pub fn max_out_of_order_segments(&mut self, max: usize) {
+        self.max_out_of_order_segments = max;
+    }
+    pub fn dns_transform<F>(&mut self, transform: F)
+    where
+        F: Fn(UdpDirection, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.dns_transform = Some(Arc::new(transform));
+    }
+    pub fn dns_port(&mut self, port: u16) {
+        self.dns_port = port;
+    }
+    pub fn shutdown_rst(&mut self, shutdown_rst: bool) {
+        self.shutdown_rst = shutdown_rst;
+    }
+    pub fn deadline_policy(&mut self, policy: ClosePolicy) {
+        self.deadline_policy = policy;
+    }
+    pub fn on_ttl_zero<F>(&mut self, callback: F)
+    where
+        F: Fn(NetworkTuple) + Send + Sync + 'static,
+    {
+        self.on_ttl_zero = Some(Arc::new(callback));
+    }
+    pub fn local_addr_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(IpAddr) -> bool + Send + Sync + 'static,
+    {
+        self.local_addr_filter = Some(Arc::new(filter));
+    }
+    pub fn window_scale(&mut self, window_scale: u8) {
+        self.window_scale = Some(window_scale);
+    }
+    pub fn frame<F>(&mut self, frame: F)
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.frame = Some(Arc::new(frame));
+    }
+    pub fn deframe<F>(&mut self, deframe: F)
+    where
+        F: Fn(&[u8]) -> Option<(usize, usize)> + Send + Sync + 'static,
+    {
+        self.deframe = Some(Arc::new(deframe));
+    }
+    pub fn max_tcp_flows(&mut self, max: usize) {
+        self.max_tcp_flows = max;
+    }
+    pub fn max_udp_flows(&mut self, max: usize) {
+        self.max_udp_flows = max;
+    }
+    pub fn udp_overflow_policy(&mut self, policy: UdpOverflowPolicy) {
+        self.udp_overflow_policy = policy;
+    }
+    pub fn max_write_segments(&mut self, max: usize) {
+        self.max_write_segments = max;
+    }
+    /// Batches pure ACKs for up to `window`. Only for devices that take
+    /// several packets in one write, like a stream split up again with
+    /// [`IpStackConfig::deframe`]. A TUN device takes one packet per write.
+    pub fn ack_batch_window(&mut self, window: Duration) {
+        self.ack_batch_window = Some(window);
+    }
+    pub fn tcp_timestamps(&mut self, enabled: bool) {
+        self.tcp_timestamps = enabled;
+    }
+    pub fn pre_accept_buffer(&mut self, packets: usize) {
+        self.pre_accept_buffer = packets;
+    }
+    pub fn randomize_flow_ids(&mut self, randomize: bool) {
+        self.randomize_flow_ids = randomize;
+    }
+    pub fn on_flow_removed<F>(&mut self, callback: F)
+    where
+        F: Fn(NetworkTuple, CloseReason) + Send + Sync + 'static,
+    {
+        self.on_flow_removed = Some(Arc::new(callback));
+    }
+    pub fn buffer_limit(&mut self, limit: usize) {
+        self.buffer_limit = limit;
+    }
+    pub fn persist_interval(&mut self, interval: Duration) {
+        self.persist_interval = interval;
+    }
+    pub fn persist_max_interval(&mut self, interval: Duration) {
+        self.persist_max_interval = interval;
+    }
+    pub fn bytes_ingress(&mut self, enabled: bool) {
+        self.bytes_ingress = enabled;
+    }
+    pub fn flow_quota(&mut self, bytes: u64) {
+        self.flow_quota = Some(bytes);
+    }
+    pub fn quota_policy(&mut self, policy: ClosePolicy) {
+        self.quota_policy = policy;
+    }
+    pub fn timer_jitter(&mut self, jitter: Duration) {
+        self.timer_jitter = jitter;
+    }
+    pub fn loopback(&mut self, loopback: bool) {
+        self.loopback = loopback;
+    }
+    pub fn reserved_bits_policy(&mut self, policy: ReservedBitsPolicy) {
+        self.reserved_bits_policy = policy;
+    }
+    /// Sets the retransmission timeout. It's fixed: round trips aren't
+    /// measured, so there's no smoothed RTT, and each ACK of new data puts
+    /// the timeout, however far it was backed off, back to `rto`. Pick it
+    /// above the longest round trip the flows will see.
+    pub fn initial_rto(&mut self, rto: Duration) {
+        self.initial_rto = rto;
+    }
+    pub fn initial_recv_window(&mut self, window: u16) {
+        self.initial_recv_window = Some(window);
+    }
+    pub fn read_watchdog<F>(&mut self, interval: Duration, callback: F)
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        self.read_watchdog = Some((interval, Arc::new(callback)));
+    }
+    pub fn min_segment_size(&mut self, size: usize) {
+        self.min_segment_size = size;
+    }
+    pub fn tinygram_policy(&mut self, policy: TinygramPolicy) {
+        self.tinygram_policy = policy;
+    }
+    pub fn flow_log_level<F>(&mut self, level: F)
+    where
+        F: Fn(NetworkTuple) -> Level + Send + Sync + 'static,
+    {
+        self.flow_log_level = Some(Arc::new(level));
+    }
+    pub fn disable_accept(&mut self, disabled: bool) {
+        self.disable_accept = disabled;
+    }
+    pub fn on_state_change<F>(&mut self, callback: F)
+    where
+        F: Fn(NetworkTuple, TcpState, TcpState) + Send + Sync + 'static,
+    {
+        self.on_state_change = Some(Arc::new(callback));
+    }
+}
+
+enum Control {
+    Shutdown,
+    Subscribe(UnboundedSender<FlowEvent>),
+    ResetAll(oneshot::Sender<()>),
+    FlowCount(oneshot::Sender<usize>),
+    Dispatch(Vec<u8>, oneshot::Sender<Dispatch>),
+    HasFlow(NetworkTuple, oneshot::Sender<bool>),
+    Stats(oneshot::Sender<IpStackStats>),
+}
+
+/// The stack's counters at one moment, see [`IpStack::stats`]. Counts are
+/// since the stack was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IpStackStats {
+    pub tcp_flows: usize,
+    pub udp_flows: usize,
+    /// Frames read from the device.
+    pub frames_read: u64,
+    /// Packets of flows written to the device. RSTs the stack sends on its
+    /// own aren't counted.
+    pub packets_sent: u64,
+    pub oversized_frames: u64,
+    pub buffer_usage: usize,
+}
+
+/// What the stack would do with a frame read from its device, see
+/// [`IpStack::dispatch`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    /// A TCP flow would be created for it.
+    NewTcpFlow,
+    /// A UDP flow would be created for it.
+    NewUdpFlow,
+    /// It would be queued for the flow it belongs to.
+    Existing,
+    /// It would be dropped, or refused with a RST or ICMP error.
+    Drop,
+    /// It would be written back to the device, see [`IpStackConfig::loopback`].
+    Reflect,
+}
+
+fn emit_closed(
+    config: &IpStackConfig,
+    subscribers: &mut Vec<UnboundedSender<FlowEvent>>,
+    tuple: NetworkTuple,
+    reason: CloseReason,
+) {
+    trace!("{} closed: {:?}", tuple, reason);
+    if let Some(ref on_flow_removed) = config.on_flow_removed {
+        on_flow_removed(tuple, reason);
+    }
+    subscribers.retain(|s| s.send(FlowEvent::Closed(tuple, reason)).is_ok());
+}
+
+struct Flow {
+    sender: UnboundedSender<NetworkPacket>,
+    // Sequence and acknowledgment numbers of a RST the peer would accept,
+    // taken from its latest ACK-bearing segment.
+    reset: Option<(u32, u32)>,
+    shared: Arc<FlowShared>,
+    queued: usize,
+    created: Instant,
+}
+
+impl Flow {
+    fn new(sender: UnboundedSender<NetworkPacket>, shared: Arc<FlowShared>) -> Flow {
+        Flow {
+            sender,
+            reset: None,
+            shared,
+            queued: 0,
+            created: Instant::now(),
+        }
+    }
+    // Accepted but never polled for `timeout`, so nothing will ever expire it.
+    fn is_unused(&self, timeout: Duration) -> bool {
+        !self.shared.polled.load(Ordering::Relaxed) && self.created.elapsed() >= timeout
+    }
+    // Whether a packet may be queued, given at most `limit` are held before
+    // the stream is first polled.
+    fn admit(&mut self, limit: usize) -> bool {
+        if self.shared.polled.load(Ordering::Relaxed) {
+            return true;
+        }
+        self.queued += 1;
+        self.queued <= limit
+    }
+    fn would_admit(&self, limit: usize) -> bool {
+        self.shared.polled.load(Ordering::Relaxed) || self.queued < limit
+    }
+    fn observe(&mut self, packet: &NetworkPacket) {
+        if let TransportHeader::Tcp(ref tcp) = packet.transport {
+            if tcp.ack && !tcp.syn {
+                let len = packet.payload.len() as u32 + tcp.fin as u32;
+                self.reset = Some((
+                    tcp.acknowledgment_number,
+                    tcp.sequence_number.wrapping_add(len),
+                ));
+            }
+        }
+    }
+}
+
+// The IP packet within a device read, as `deframe` or `packet_info` say.
+fn unframe<'a>(config: &IpStackConfig, offset: usize, data: &'a [u8]) -> Option<&'a [u8]> {
+    match config.deframe {
+        Some(ref deframe) => match deframe(data) {
+            Some((start, end)) if start <= end && end <= data.len() => Some(&data[start..end]),
+            _ => None,
+        },
+        None => data.get(offset..),
+    }
+}
+
+// Flows of each protocol in the stack's map, kept up to date as flows are
+// added and removed so limits are checked without walking the map.
+#[derive(Default)]
+struct FlowCounts {
+    tcp: usize,
+    udp: usize,
+}
+
+impl FlowCounts {
+    fn get(&self, tcp: bool) -> usize {
+        if tcp {
+            self.tcp
+        } else {
+            self.udp
+        }
+    }
+    fn add(&mut self, tcp: bool) {
+        if tcp {
+            self.tcp += 1;
+        } else {
+            self.udp += 1;
+        }
+    }
+    fn remove(&mut self, tcp: bool) {
+        if tcp {
+            self.tcp -= 1;
+        } else {
+            self.udp -= 1;
+        }
+    }
+}
+
+// Whether a new flow of the given protocol would be refused.
+fn at_flow_limit(config: &IpStackConfig, counts: &FlowCounts, arena: &Arena, tcp: bool) -> bool {
+    let limit = if tcp {
+        config.max_tcp_flows
+    } else {
+        config.max_udp_flows
+    };
+    config.disable_accept || counts.get(tcp) >= limit || (tcp && arena.available() == 0)
+}
+
+// Follows the device read arm of the stack's loop without acting on anything.
+fn dispatch(
+    config: &IpStackConfig,
+    streams: &HashMap<NetworkTuple, Flow>,
+    counts: &FlowCounts,
+    arena: &Arena,
+    offset: usize,
+    data: &[u8],
+) -> Dispatch {
+    if data.is_empty() || data.len() > config.read_mtu + offset {
+        return Dispatch::Drop;
+    }
+    let Some(frame) = unframe(config, offset, data) else {
+        return Dispatch::Drop;
+    };
+    let Ok(packet) = NetworkPacket::parse(frame) else {
+        return Dispatch::Drop;
+    };
+    if packet.tcp_reserved_bits(frame) != 0
+        && config.reserved_bits_policy == ReservedBitsPolicy::Drop
+    {
+        return Dispatch::Drop;
+    }
+    if config.loopback {
+        return Dispatch::Reflect;
+    }
+    if packet.ttl() == 0 && config.on_ttl_zero.is_some() {
+        return Dispatch::Drop;
+    }
+    let tuple = packet.network_tuple();
+    if let Some(flow) = streams.get(&tuple) {
+        return if flow.would_admit(config.pre_accept_buffer) {
+            Dispatch::Existing
+        } else {
+            Dispatch::Drop
+        };
+    }
+    // An unsolicited SYN-ACK is answered with a RST, never a flow.
+    if let IpStackPacketProtocol::Tcp(h) = packet.transport_protocol() {
+        if h.inner().syn && h.inner().ack {
+            return Dispatch::Drop;
+        }
+    }
+    if config
+        .local_addr_filter
+        .as_ref()
+        .is_some_and(|filter| !filter(packet.dst_addr().ip()))
+        || at_flow_limit(config, counts, arena, tuple.tcp)
+    {
+        return Dispatch::Drop;
+    }
+    if tuple.tcp {
+        Dispatch::NewTcpFlow
+    } else {
+        Dispatch::NewUdpFlow
+    }
+}
+
+fn framed_bytes(config: &IpStackConfig, packet: &NetworkPacket) -> Result<Vec<u8>, IpStackError> {
+    #[cfg(not(target_os = "windows"))]
+    let mut packet_byte = packet.to_bytes()?;
+    #[cfg(target_os = "windows")]
+    let packet_byte = packet.to_bytes()?;
+    if let Some(ref frame) = config.frame {
+        return Ok(frame(&packet_byte));
+    }
+    #[cfg(not(target_os = "windows"))]
+    if config.packet_info {
+        if matches!(packet.ip, IpHeader::Version4(..)) {
+            packet_byte.splice(0..0, [TUN_FLAGS, TUN_PROTO_IP4].concat());
+        } else {
+            packet_byte.splice(0..0, [TUN_FLAGS, TUN_PROTO_IP6].concat());
+        }
+    }
+    Ok(packet_byte)
+}
+
+// With `bytes_len`, reads into `bytes` (cleared first) up to that many bytes
+// rather than into `buffer`.
+async fn read_device<D>(
+    device: &mut D,
+    buffer: &mut [u8],
+    bytes: &mut BytesMut,
+    bytes_len: Option<usize>,
+) -> std::io::Result<usize>
+where
+    D: AsyncRead + std::marker::Unpin,
+{
+    let Some(len) = bytes_len else {
+        return device.read(buffer).await;
+    };
+    bytes.clear();
+    bytes.reserve(len);
+    device.read_buf(&mut (&mut *bytes).limit(len)).await
+}
+
+// Writes one frame in full before anything else reaches the device, so a
+// device accepting a few bytes per write still sees whole frames. Interrupted
+// and would-block writes are retried from where they stopped.
+async fn write_frame<D>(device: &mut D, frame: &[u8]) -> std::io::Result<()>
+where
+    D: AsyncWrite + std::marker::Unpin,
+{
+    let mut written = 0;
+    while written < frame.len() {
+        match device.write(&frame[written..]).await {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+                ) =>
+            {
+                tokio::task::yield_now().await
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    device.flush().await
+}
+
+type Mirror = Box<dyn AsyncWrite + std::marker::Unpin + std::marker::Send>;
+
+// Frames copied to the mirror wait here for its own task to write them, so a
+// slow mirror never holds up the device.
+const MIRROR_QUEUE: usize = 1024;
+
+struct MirrorQueue {
+    sender: mpsc::Sender<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl MirrorQueue {
+    fn spawn(mut mirror: Mirror, dropped: Arc<AtomicU64>) -> MirrorQueue {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(MIRROR_QUEUE);
+        tokio::spawn(async move {
+            while let Some(frame) = receiver.recv().await {
+                if let Err(e) = write_frame(&mut mirror, &frame).await {
+                    warn!("mirror: {}", e);
+                }
+            }
+        });
+        MirrorQueue { sender, dropped }
+    }
+}
+
+// Pure ACKs held back to be written together, as
+// `IpStackConfig::ack_batch_window` says.
+#[derive(Default)]
+struct AckBatch {
+    frames: Vec<u8>,
+    count: u64,
+}
+
+impl AckBatch {
+    fn push(&mut self, frame: &[u8]) {
+        self.frames.extend_from_slice(frame);
+        self.count += 1;
+    }
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.count = 0;
+    }
+    // Writes whatever is held, returning how many ACKs reached the device.
+    async fn flush<D>(&mut self, device: &mut D, mirror: &Option<MirrorQueue>) -> u64
+    where
+        D: AsyncWrite + std::marker::Unpin,
+    {
+        if self.is_empty() {
+            return 0;
+        }
+        let written = match egress(device, mirror, &self.frames).await {
+            Ok(()) => self.count,
+            Err(e) => {
+                error!("{}", e);
+                0
+            }
+        };
+        self.clear();
+        written
+    }
+}
+
+// Writes a frame to the device, then queues a copy of it for the mirror, if
+// any. Copies the mirror has no room for are dropped and counted.
+async fn egress<D>(
+    device: &mut D,
+    mirror: &Option<MirrorQueue>,
+    frame: &[u8],
+) -> std::io::Result<()>
+where
+    D: AsyncWrite + std::marker::Unpin,
+{
+    write_frame(device, frame).await?;
+    if let Some(mirror) = mirror {
+        if mirror.sender.try_send(frame.to_vec()).is_err() {
+            mirror.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    Ok(())
 }
 
 pub struct IpStack {
-    accept_receiver: UnboundedReceiver<IpStackStream>,
+    accept_receiver: Option<UnboundedReceiver<IpStackStream>>,
+    control_sender: UnboundedSender<Control>,
+    handle: JoinHandle<()>,
+    oversized_frames: Arc<AtomicU64>,
+    mirror_dropped: Arc<AtomicU64>,
+    arena: Arc<Arena>,
+    ready: watch::Receiver<bool>,
 }
 
 impl IpStack {
-    pub fn new<D>(config: IpStackConfig, mut device: D) -> IpStack
+    pub fn new<D>(config: IpStackConfig, device: D) -> IpStack
+    where
+        D: AsyncRead + AsyncWrite + std::marker::Unpin + std::marker::Send + 'static,
+    {
+        IpStack::spawn(config, device, None)
+    }
+    /// Like [`IpStack::new`], and writes a copy of every frame written to
+    /// `device` to `mirror` as well. The mirror is written on a task of its
+    /// own: copies it falls too far behind to take are dropped, see
+    /// [`IpStack::mirror_dropped_frames`], and failed writes to it are logged
+    /// and otherwise ignored.
+    pub fn with_mirror<D, M>(config: IpStackConfig, device: D, mirror: M) -> IpStack
+    where
+        D: AsyncRead + AsyncWrite + std::marker::Unpin + std::marker::Send + 'static,
+        M: AsyncWrite + std::marker::Unpin + std::marker::Send + 'static,
+    {
+        IpStack::spawn(config, device, Some(Box::new(mirror)))
+    }
+    fn spawn<D>(config: IpStackConfig, mut device: D, mirror: Option<Mirror>) -> IpStack
     where
         D: AsyncRead + AsyncWrite + std::marker::Unpin + std::marker::Send + 'static,
     {
-        let (accept_sender, accept_receiver) = mpsc::unbounded_channel::<IpStackStream>();
+        let (accept_sender, accept_receiver) = if config.disable_accept {
+            (None, None)
+        } else {
+            let (sender, receiver) = mpsc::unbounded_channel::<IpStackStream>();
+            (Some(sender), Some(receiver))
+        };
+        let (control_sender, mut control_receiver) = mpsc::unbounded_channel::<Control>();
+        let oversized_frames = Arc::new(AtomicU64::new(0));
+        let oversized = oversized_frames.clone();
+        let mirror_dropped = Arc::new(AtomicU64::new(0));
+        let mirror = mirror.map(|mirror| MirrorQueue::spawn(mirror, mirror_dropped.clone()));
+        let arena = Arc::new(Arena::new(config.buffer_limit));
+        let flow_arena = arena.clone();
+        let (ready_sender, ready) = watch::channel(false);
 
-        tokio::spawn(async move {
-            let mut streams: HashMap<NetworkTuple, UnboundedSender<NetworkPacket>> = HashMap::new();
-            let mut buffer = [0u8; u16::MAX as usize];
+        let handle = tokio::spawn(async move {
+            let mut ready_sender = Some(ready_sender);
+            let mut streams: HashMap<NetworkTuple, Flow> = HashMap::new();
+            let mut counts = FlowCounts::default();
+            let mut subscribers: Vec<UnboundedSender<FlowEvent>> = Vec::new();
+            let offset = if config.packet_info && cfg!(not(target_os = "windows")) {
+                4
+            } else {
+                0
+            };
+            // One spare byte tells a frame that filled the buffer exactly from
+            // one that was cut short.
+            let max_frame = config.read_mtu + offset;
+            let mut buffer = if config.bytes_ingress {
+                Vec::new()
+            } else {
+                vec![0u8; max_frame + 1]
+            };
+            let mut bytes = BytesMut::new();
 
             let (pkt_sender, mut pkt_receiver) = mpsc::unbounded_channel::<NetworkPacket>();
+            let reap_period = cmp::max(
+                cmp::min(config.tcp_timeout, config.udp_timeout) / 4,
+                MIN_REAP_PERIOD,
+            );
+            let mut reap = tokio::time::interval(reap_period);
+            reap.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut ack_batch = AckBatch::default();
+            let ack_flush = tokio::time::sleep(Duration::ZERO);
+            tokio::pin!(ack_flush);
+            // Rather than being reset on every read, the watchdog rechecks
+            // `last_read` as it fires.
+            let mut last_read = Instant::now();
+            let (mut frames_read, mut packets_sent) = (0u64, 0u64);
+            let read_stall = tokio::time::sleep(
+                config
+                    .read_watchdog
+                    .as_ref()
+                    .map_or(Duration::ZERO, |(interval, _)| *interval),
+            );
+            tokio::pin!(read_stall);
             loop {
                 // dbg!(streams.len());
                 select! {
-                    Ok(n) = device.read(&mut buffer) => {
-                        let offset = if config.packet_info && cfg!(not(target_os = "windows")) {4} else {0};
+                    Ok(n) = read_device(&mut device, &mut buffer, &mut bytes, config.bytes_ingress.then_some(max_frame + 1)) => {
+                        let buffer: &[u8] = if config.bytes_ingress { &bytes } else { &buffer };
                         // dbg!(&buffer[offset..n]);
-                        let Ok(packet) = NetworkPacket::parse(&buffer[offset..n])else{
-                            trace!("parse error");
+                        if n == 0 {
+                            trace!("device closed");
+                            break;
+                        }
+                        last_read = Instant::now();
+                        frames_read += 1;
+                        if let Some(ready) = ready_sender.take() {
+                            _ = ready.send(true);
+                        }
+                        if n > max_frame {
+                            oversized.fetch_add(1, Ordering::Relaxed);
+                            warn!("{}", IpStackError::OversizedFrame);
                             continue;
+                        }
+                        let Some(frame) = unframe(&config, offset, &buffer[..n]) else {
+                            trace!("deframe error");
+                            continue;
+                        };
+                        let mut packet = match NetworkPacket::parse(frame) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                trace!("parse error: {}", e);
+                                continue;
+                            }
                         };
-                        match streams.entry(packet.network_tuple()){
-                            Occupied(entry) =>{
+                        if packet.tcp_reserved_bits(frame) != 0 {
+                            match config.reserved_bits_policy {
+                                ReservedBitsPolicy::Pass => {}
+                                ReservedBitsPolicy::Ignore => packet.clear_tcp_reserved_bits(),
+                                ReservedBitsPolicy::Drop => {
+                                    trace!("reserved bits set");
+                                    continue;
+                                }
+                            }
+                        }
+                        if config.loopback {
+                            match framed_bytes(&config, &packet.reflect()) {
+                                Ok(packet_byte) => if let Err(e) = egress(&mut device, &mirror, &packet_byte).await {
+                                    error!("{}", e);
+                                },
+                                Err(e) => trace!("{}", e),
+                            }
+                            continue;
+                        }
+                        if packet.ttl() == 0 {
+                            if let Some(ref on_ttl_zero) = config.on_ttl_zero {
+                                on_ttl_zero(packet.network_tuple());
+                                continue;
+                            }
+                        }
+                        let tuple = packet.network_tuple();
+                        if !streams.contains_key(&tuple) {
+                            if config.local_addr_filter.as_ref().is_some_and(|filter| !filter(packet.dst_addr().ip())){
+                                trace!("unassigned destination {}", packet.dst_addr());
+                                continue;
+                            }
+                            if at_flow_limit(&config, &counts, &flow_arena, tuple.tcp) {
+                                trace!("flow limit reached for {}", tuple);
+                                let refusal = match packet.transport_protocol() {
+                                    IpStackPacketProtocol::Tcp(_) => NetworkPacket::tcp_reset_for(&packet),
+                                    IpStackPacketProtocol::Udp => match config.udp_overflow_policy {
+                                        UdpOverflowPolicy::Drop => continue,
+                                        UdpOverflowPolicy::PortUnreachable => NetworkPacket::port_unreachable(&packet, frame),
+                                    },
+                                };
+                                match refusal.and_then(|p| framed_bytes(&config, &p)) {
+                                    Ok(packet_byte) => if let Err(e) = egress(&mut device, &mirror, &packet_byte).await {
+                                        error!("{}", e);
+                                    },
+                                    Err(e) => trace!("{}", e),
+                                }
+                                continue;
+                            }
+                        }
+                        match streams.entry(tuple){
+                            Occupied(mut entry) =>{
+                                if !entry.get_mut().admit(config.pre_accept_buffer) {
+                                    trace!("pre-accept buffer full for {}", tuple);
+                                    continue;
+                                }
                                 let t = packet.transport_protocol();
-                                if let Err(_x) = entry.get().send(packet){
+                                entry.get_mut().observe(&packet);
+                                if let Err(_x) = entry.get().sender.send(packet){
                                     trace!("{}", _x);
                                     match t{
                                         IpStackPacketProtocol::Tcp(_t) => {
@@ -121,11 +966,30 @@ impl IpStack {
                             }
                             Vacant(entry) => {
                                 match packet.transport_protocol(){
+                                    IpStackPacketProtocol::Tcp(h) if h.inner().syn && h.inner().ack => {
+                                        // No flow is ever in SYN-SENT, so a SYN-ACK is
+                                        // unsolicited: reset it as in CLOSED (RFC 9293 3.10.7.1).
+                                        trace!("unsolicited SYN-ACK for {}", tuple);
+                                        match NetworkPacket::tcp_reset_for(&packet).and_then(|p| framed_bytes(&config, &p)) {
+                                            Ok(packet_byte) => if let Err(e) = egress(&mut device, &mirror, &packet_byte).await {
+                                                error!("{}", e);
+                                            },
+                                            Err(e) => trace!("{}", e),
+                                        }
+                                    }
                                     IpStackPacketProtocol::Tcp(h) => {
-                                        match IpStackTcpStream::new(packet.src_addr(),packet.dst_addr(),h, pkt_sender.clone(),config.mtu,config.tcp_timeout).await{
-                                            Ok(stream) => {
-                                                entry.insert(stream.stream_sender());
-                                                accept_sender.send(IpStackStream::Tcp(stream)).unwrap();
+                                        // Until the SYN is answered, only a RST acking it is valid.
+                                        let syn_ack = h.inner().sequence_number.wrapping_add(1);
+                                        match IpStackTcpStream::new(packet.src_addr(),packet.dst_addr(),h, pkt_sender.clone(),&config, flow_arena.clone()).await{
+                                            Ok(mut stream) => {
+                                                stream.set_traffic_class(packet.traffic_class());
+                                                let mut flow = Flow::new(stream.stream_sender(), stream.shared());
+                                                flow.reset = Some((0, syn_ack));
+                                                entry.insert(flow);
+                                                counts.add(true);
+                                                if let Some(ref accept_sender) = accept_sender {
+                                                    accept_sender.send(IpStackStream::Tcp(stream)).unwrap();
+                                                }
                                             }
                                             Err(e) => {
                                                 error!("{}",e);
@@ -133,51 +997,302 @@ impl IpStack {
                                         }
                                     }
                                     IpStackPacketProtocol::Udp => {
-                                        let stream = IpStackUdpStream::new(packet.src_addr(),packet.dst_addr(),packet.payload, pkt_sender.clone(),config.mtu,config.udp_timeout);
-                                        entry.insert(stream.stream_sender());
-                                        accept_sender.send(IpStackStream::Udp(stream)).unwrap();
+                                        let traffic_class = packet.traffic_class();
+                                        let mut stream = IpStackUdpStream::new(packet.src_addr(),packet.dst_addr(),packet.payload, pkt_sender.clone(),&config);
+                                        stream.set_traffic_class(traffic_class);
+                                        entry.insert(Flow::new(stream.stream_sender(), stream.shared()));
+                                        counts.add(false);
+                                        if let Some(ref accept_sender) = accept_sender {
+                                            accept_sender.send(IpStackStream::Udp(stream)).unwrap();
+                                        }
                                     }
                                 }
                             }
                         }
                     }
-                    Some(packet) = pkt_receiver.recv() => {
+                    packet = pkt_receiver.recv() => {
+                        // The loop holds a sender itself, but a closed channel
+                        // must never leave this arm spinning.
+                        let Some(packet) = packet else {
+                            break;
+                        };
                         if packet.ttl() == 0{
-                            streams.remove(&packet.reverse_network_tuple());
+                            let tuple = packet.reverse_network_tuple();
+                            if streams.remove(&tuple).is_some() {
+                                counts.remove(tuple.tcp);
+                                emit_closed(&config, &mut subscribers, tuple, CloseReason::from_marker(&packet));
+                            }
                             continue;
                         }
-                        #[cfg(not(target_os = "windows"))]
-                        let Ok(mut packet_byte) = packet.to_bytes() else{
+                        let Ok(packet_byte) = framed_bytes(&config, &packet) else{
                             trace!("to_bytes error");
                             continue;
                         };
-                        #[cfg(target_os = "windows")]
-                        let Ok(packet_byte) = packet.to_bytes() else{
-                            trace!("to_bytes error");
+                        if let Some(window) = config.ack_batch_window.filter(|_| packet.is_pure_ack()) {
+                            if ack_batch.is_empty() {
+                                ack_flush.as_mut().reset(Instant::now() + window);
+                            }
+                            ack_batch.push(&packet_byte);
+                            continue;
+                        }
+                        packets_sent += ack_batch.flush(&mut device, &mirror).await;
+                        match egress(&mut device, &mirror, &packet_byte).await {
+                            Ok(()) => packets_sent += 1,
+                            Err(e) => error!("{}", e),
+                        }
+                    }
+                    _ = &mut ack_flush, if !ack_batch.is_empty() => {
+                        packets_sent += ack_batch.flush(&mut device, &mirror).await;
+                    }
+                    _ = &mut read_stall, if config.read_watchdog.is_some() => {
+                        let Some((interval, ref on_stall)) = config.read_watchdog else {
                             continue;
                         };
-                        #[cfg(not(target_os = "windows"))]
-                        if config.packet_info {
-                            if packet.src_addr().is_ipv4(){
-                                packet_byte.splice(0..0, [TUN_FLAGS, TUN_PROTO_IP4].concat());
-                            } else{
-                                packet_byte.splice(0..0, [TUN_FLAGS, TUN_PROTO_IP6].concat());
+                        let now = Instant::now();
+                        let stalled = now - last_read;
+                        if stalled >= interval {
+                            warn!("no device read for {:?}", stalled);
+                            on_stall(stalled);
+                            read_stall.as_mut().reset(now + interval);
+                        } else {
+                            read_stall.as_mut().reset(last_read + interval);
+                        }
+                    }
+                    _ = reap.tick() => {
+                        let unused: Vec<NetworkTuple> = streams
+                            .iter()
+                            .filter(|(tuple, flow)| flow.is_unused(if tuple.tcp { config.tcp_timeout } else { config.udp_timeout }))
+                            .map(|(tuple, _)| *tuple)
+                            .collect();
+                        for tuple in unused {
+                            let Some(flow) = streams.remove(&tuple) else {
+                                continue;
+                            };
+                            counts.remove(tuple.tcp);
+                            flow.shared.reaped.store(true, Ordering::Relaxed);
+                            if let Some((seq, ack)) = flow.reset.filter(|_| tuple.tcp) {
+                                if let Ok(packet_byte) = NetworkPacket::tcp_reset(tuple.dst, tuple.src, seq, Some(ack))
+                                    .and_then(|p| framed_bytes(&config, &p)) {
+                                    if let Err(e) = egress(&mut device, &mirror, &packet_byte).await {
+                                        error!("{}", e);
+                                    }
+                                }
+                            }
+                            emit_closed(&config, &mut subscribers, tuple, CloseReason::IdleTimeout);
+                        }
+                    }
+                    Some(control) = control_receiver.recv() => {
+                        match control {
+                            Control::Shutdown => {
+                                ack_batch.flush(&mut device, &mirror).await;
+                                if config.shutdown_rst {
+                                    let established = streams.iter().filter(|(t, flow)| t.tcp && flow.shared.established.load(Ordering::Relaxed));
+                                    for (tuple, flow) in established {
+                                        let Some((seq, ack)) = flow.reset else {
+                                            continue;
+                                        };
+                                        let Ok(packet_byte) = NetworkPacket::tcp_reset(tuple.dst, tuple.src, seq, Some(ack))
+                                            .and_then(|p| framed_bytes(&config, &p)) else {
+                                            continue;
+                                        };
+                                        if let Err(e) = egress(&mut device, &mirror, &packet_byte).await {
+                                            error!("{}", e);
+                                            break;
+                                        }
+                                    }
+                                }
+                                break;
+                            }
+                            Control::Subscribe(subscriber) => subscribers.push(subscriber),
+                            Control::ResetAll(done) => {
+                                counts = FlowCounts::default();
+                                for (tuple, flow) in streams.drain() {
+                                    flow.shared.reaped.store(true, Ordering::Relaxed);
+                                    if let Some((seq, ack)) = flow.reset.filter(|_| tuple.tcp) {
+                                        if let Ok(packet_byte) = NetworkPacket::tcp_reset(tuple.dst, tuple.src, seq, Some(ack))
+                                            .and_then(|p| framed_bytes(&config, &p)) {
+                                            if let Err(e) = egress(&mut device, &mirror, &packet_byte).await {
+                                                error!("{}", e);
+                                            }
+                                        }
+                                    }
+                                    emit_closed(&config, &mut subscribers, tuple, CloseReason::Reset);
+                                }
+                                // Whatever the old flows had queued is stale now.
+                                ack_batch.clear();
+                                while pkt_receiver.try_recv().is_ok() {}
+                                _ = done.send(());
+                            }
+                            Control::FlowCount(count) => _ = count.send(streams.len()),
+                            Control::Dispatch(frame, decision) => _ = decision.send(dispatch(&config, &streams, &counts, &flow_arena, offset, &frame)),
+                            Control::HasFlow(tuple, found) => _ = found.send(streams.contains_key(&tuple)),
+                            Control::Stats(stats) => {
+                                _ = stats.send(IpStackStats {
+                                    tcp_flows: counts.tcp,
+                                    udp_flows: counts.udp,
+                                    frames_read,
+                                    packets_sent,
+                                    oversized_frames: oversized.load(Ordering::Relaxed),
+                                    buffer_usage: flow_arena.used(),
+                                });
                             }
                         }
-                        device.write_all(&packet_byte).await.unwrap();
-                        // device.flush().await.unwrap();
                     }
                 }
             }
+            for tuple in streams.keys() {
+                emit_closed(&config, &mut subscribers, *tuple, CloseReason::Shutdown);
+            }
         });
 
-        IpStack { accept_receiver }
+        IpStack {
+            accept_receiver,
+            control_sender,
+            handle,
+            oversized_frames,
+            mirror_dropped,
+            arena,
+            ready,
+        }
     }
+    /// Resolves once the stack's task has read its first frame from the
+    /// device, or has stopped without reading one.
+    pub async fn wait_ready(&self) {
+        _ = self.ready.clone().wait_for(|ready| *ready).await;
+    }
+    /// Returns the next new flow. A TCP stream is returned as soon as its SYN
+    /// arrives, and the handshake runs once the stream is first polled, by a
+    /// read, a write or [`IpStackTcpStream::established`]. Until then:
+    ///
+    /// - segments from the peer are queued, up to
+    ///   [`IpStackConfig::pre_accept_buffer`], and read in order afterwards;
+    /// - writes wait for the handshake to complete rather than being sent or
+    ///   dropped early.
+    ///
+    /// With [`IpStackConfig::disable_accept`] it fails at once with
+    /// [`IpStackError::AcceptDisabled`].
     pub async fn accept(&mut self) -> Result<IpStackStream, IpStackError> {
-        if let Some(s) = self.accept_receiver.recv().await {
+        let Some(ref mut accept_receiver) = self.accept_receiver else {
+            return Err(IpStackError::AcceptDisabled);
+        };
+        if let Some(s) = accept_receiver.recv().await {
             Ok(s)
         } else {
             Err(IpStackError::AcceptError)
         }
     }
+    /// Waits for a stream like [`IpStack::accept`], then takes up to `max`
+    /// from those already queued. It's empty only once the stack has stopped,
+    /// with a `max` of 0, or with accepting disabled.
+    pub async fn accept_many(&mut self, max: usize) -> Vec<IpStackStream> {
+        let mut streams = Vec::new();
+        if let Some(ref mut accept_receiver) = self.accept_receiver {
+            accept_receiver.recv_many(&mut streams, max).await;
+        }
+        streams
+    }
+    /// Takes up to `max` of the streams already queued, without waiting.
+    pub fn try_accept_many(&mut self, max: usize) -> Vec<IpStackStream> {
+        let mut streams = Vec::new();
+        let Some(ref mut accept_receiver) = self.accept_receiver else {
+            return streams;
+        };
+        while streams.len() < max {
+            let Ok(stream) = accept_receiver.try_recv() else {
+                break;
+            };
+            streams.push(stream);
+        }
+        streams
+    }
+    /// Number of device reads dropped for exceeding `read_mtu`.
+    pub fn oversized_frames(&self) -> u64 {
+        self.oversized_frames.load(Ordering::Relaxed)
+    }
+    /// Number of frames not copied to the mirror for lack of room.
+    pub fn mirror_dropped_frames(&self) -> u64 {
+        self.mirror_dropped.load(Ordering::Relaxed)
+    }
+    /// Bytes currently held for reassembly across all TCP flows, bounded by
+    /// [`IpStackConfig::buffer_limit`].
+    pub fn buffer_usage(&self) -> usize {
+        self.arena.used()
+    }
+    /// Returns a receiver of [`FlowEvent`]s for flows closed from now on.
+    pub fn flow_events(&self) -> UnboundedReceiver<FlowEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        _ = self.control_sender.send(Control::Subscribe(sender));
+        receiver
+    }
+    /// Number of flows the stack currently tracks.
+    pub async fn flow_count(&self) -> usize {
+        let (sender, receiver) = oneshot::channel();
+        _ = self.control_sender.send(Control::FlowCount(sender));
+        receiver.await.unwrap_or(0)
+    }
+    /// Whether the stack currently tracks a flow for `tuple`.
+    pub async fn has_flow(&self, tuple: &NetworkTuple) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        _ = self.control_sender.send(Control::HasFlow(*tuple, sender));
+        receiver.await.unwrap_or(false)
+    }
+    /// Snapshot of the stack's counters, or `None` once it has stopped.
+    pub async fn stats(&self) -> Option<IpStackStats> {
+        let (sender, receiver) = oneshot::channel();
+        _ = self.control_sender.send(Control::Stats(sender));
+        receiver.await.ok()
+    }
+    /// Spawns a task sending `sender` a snapshot of the stack's counters every
+    /// `interval`, the first right away. Intervals under 10ms are taken as
+    /// 10ms. It ends once the stack stops or the receiver is dropped.
+    pub fn emit_stats(
+        &self,
+        interval: Duration,
+        sender: mpsc::Sender<IpStackStats>,
+    ) -> JoinHandle<()> {
+        let control_sender = self.control_sender.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(cmp::max(interval, MIN_STATS_PERIOD));
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                ticks.tick().await;
+                let (stats, receiver) = oneshot::channel();
+                if control_sender.send(Control::Stats(stats)).is_err() {
+                    break;
+                }
+                let Ok(stats) = receiver.await else {
+                    break;
+                };
+                if sender.send(stats).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+    /// Reports what the stack would do with `frame`, were it read from the
+    /// device now, without doing any of it.
+    pub async fn dispatch(&self, frame: &[u8]) -> Dispatch {
+        let (sender, receiver) = oneshot::channel();
+        _ = self
+            .control_sender
+            .send(Control::Dispatch(frame.to_vec(), sender));
+        receiver.await.unwrap_or(Dispatch::Drop)
+    }
+    /// Resets every TCP flow's peer, forgets every flow and drops whatever
+    /// was queued for the device, leaving the stack as if newly created.
+    /// Streams still held find their flows closed from then on.
+    pub async fn reset_all(&self) {
+        let (sender, receiver) = oneshot::channel();
+        if self.control_sender.send(Control::ResetAll(sender)).is_ok() {
+            _ = receiver.await;
+        }
+    }
+    /// Stops the background task and waits for it to exit. With
+    /// [`IpStackConfig::shutdown_rst`] set, the peer of every established TCP
+    /// flow is sent a RST first.
+    pub async fn shutdown(self) {
+        if self.control_sender.send(Control::Shutdown).is_ok() {
+            _ = self.handle.await;
+        }
+    }
 }
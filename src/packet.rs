@@ -1,15 +1,43 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    cmp,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
-use etherparse::{IpHeader, PacketHeaders, TcpHeader, TransportHeader};
+use etherparse::{
+    icmpv4::DestUnreachableHeader, icmpv6::DestUnreachableCode, Icmpv4Header, Icmpv4Type,
+    Icmpv6Header, Icmpv6Type, IpHeader, Ipv4Extensions, Ipv4Header, Ipv6Extensions, Ipv6Header,
+    PacketHeaders, SerializedSize, TcpHeader, TransportHeader, TCP_MINIMUM_HEADER_SIZE,
+};
 
-use crate::error::IpStackError;
+use crate::{error::IpStackError, TTL};
 
-#[derive(Eq, Hash, PartialEq, Debug)]
+/// Identifies a flow the same way the stack keys it internally.
+#[derive(Eq, Hash, PartialEq, Debug, Clone, Copy)]
 pub struct NetworkTuple {
     pub src: SocketAddr,
     pub dst: SocketAddr,
     pub tcp: bool,
 }
+
+impl NetworkTuple {
+    pub fn new(src: SocketAddr, dst: SocketAddr, tcp: bool) -> Self {
+        NetworkTuple { src, dst, tcp }
+    }
+    pub fn reverse(&self) -> Self {
+        NetworkTuple {
+            src: self.dst,
+            dst: self.src,
+            tcp: self.tcp,
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkTuple {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let protocol = if self.tcp { "tcp" } else { "udp" };
+        write!(f, "{} {} -> {}", protocol, self.src, self.dst)
+    }
+}
 pub mod tcp_flags {
     pub const CWR: u8 = 0b10000000;
     pub const ECE: u8 = 0b01000000;
@@ -33,7 +61,8 @@ pub struct NetworkPacket {
 
 impl NetworkPacket {
     pub fn parse(buf: &[u8]) -> Result<Self, IpStackError> {
-        let p = PacketHeaders::from_ip_slice(buf).map_err(|_| IpStackError::InvalidPacket)?;
+        let buf = &buf[..ip_total_length(buf)?];
+        let p = PacketHeaders::from_ip_slice(buf).map_err(|_| parse_error(buf))?;
         let ip = p.ip.ok_or(IpStackError::InvalidPacket)?;
         let transport = p
             .transport
@@ -41,7 +70,27 @@ impl NetworkPacket {
                 (matches!(t, TransportHeader::Tcp(_)) || matches!(t, TransportHeader::Udp(_)))
             })
             .ok_or(IpStackError::UnsupportedTransportProtocol)?;
-        let payload = p.payload.to_vec();
+        if let TransportHeader::Tcp(ref tcp) = transport {
+            TcpOptions::parse(tcp.options())?;
+        }
+        // A zero UDP checksum means none was computed, which only IPv4 allows
+        // (RFC 8200 section 8.1).
+        if let (IpHeader::Version6(..), TransportHeader::Udp(ref udp)) = (&ip, &transport) {
+            if udp.checksum == 0 {
+                return Err(IpStackError::InvalidPacket);
+            }
+        }
+        let mut payload = p.payload;
+        // A jumbogram's length lives in the hop-by-hop options and covers
+        // everything after the fixed IPv6 header.
+        if let Some(jumbo_len) = jumbo_payload_length(&ip) {
+            let headers_len = buf.len() - Ipv6Header::SERIALIZED_SIZE - payload.len();
+            payload = (jumbo_len as usize)
+                .checked_sub(headers_len)
+                .and_then(|len| payload.get(..len))
+                .ok_or(IpStackError::InvalidPacket)?;
+        }
+        let payload = payload.to_vec();
         Ok(NetworkPacket {
             ip,
             transport,
@@ -93,14 +142,12 @@ impl NetworkPacket {
         }
     }
     pub fn reverse_network_tuple(&self) -> NetworkTuple {
-        NetworkTuple {
-            src: self.dst_addr(),
-            dst: self.src_addr(),
-            tcp: matches!(self.transport, TransportHeader::Tcp(_)),
-        }
+        self.network_tuple().reverse()
     }
     pub fn to_bytes(&self) -> Result<Vec<u8>, IpStackError> {
         let mut buf = Vec::new();
+        // The IPv4 checksum is recomputed here over the whole header,
+        // options included, so edited headers never go out stale.
         self.ip
             .write(&mut buf)
             .map_err(IpStackError::PacketWriteError)?;
@@ -110,12 +157,306 @@ impl NetworkPacket {
         buf.extend_from_slice(&self.payload);
         Ok(buf)
     }
+    // Swaps source and destination. Checksums sum both the same way, so they
+    // stay valid.
+    pub(crate) fn reflect(mut self) -> NetworkPacket {
+        match self.ip {
+            IpHeader::Version4(ref mut ip, _) => {
+                std::mem::swap(&mut ip.source, &mut ip.destination)
+            }
+            IpHeader::Version6(ref mut ip, _) => {
+                std::mem::swap(&mut ip.source, &mut ip.destination)
+            }
+        }
+        match self.transport {
+            TransportHeader::Tcp(ref mut tcp) => {
+                std::mem::swap(&mut tcp.source_port, &mut tcp.destination_port)
+            }
+            TransportHeader::Udp(ref mut udp) => {
+                std::mem::swap(&mut udp.source_port, &mut udp.destination_port)
+            }
+            _ => {}
+        }
+        self
+    }
+    // The reserved bits of a TCP header, NS included, read from the frame
+    // the packet was parsed from, as parsing keeps only NS.
+    pub(crate) fn tcp_reserved_bits(&self, frame: &[u8]) -> u8 {
+        if !matches!(self.transport, TransportHeader::Tcp(_)) {
+            return 0;
+        }
+        frame
+            .get(self.ip.header_len() + 12)
+            .map_or(0, |offset_and_reserved| offset_and_reserved & 0x0f)
+    }
+    pub(crate) fn clear_tcp_reserved_bits(&mut self) {
+        if let TransportHeader::Tcp(ref mut tcp) = self.transport {
+            tcp.ns = false;
+        }
+    }
+    // An ACK carrying neither data nor any other control flag.
+    pub(crate) fn is_pure_ack(&self) -> bool {
+        matches!(self.transport, TransportHeader::Tcp(ref tcp)
+            if tcp.ack && !tcp.syn && !tcp.fin && !tcp.rst && self.payload.is_empty())
+    }
     pub fn ttl(&self) -> u8 {
         match &self.ip {
             IpHeader::Version4(ip, _) => ip.time_to_live,
             IpHeader::Version6(ip, _) => ip.hop_limit,
         }
     }
+    // The DSCP in the top six bits of the traffic class byte. The ECN bits
+    // are left Not-ECT, as the stack doesn't do ECN.
+    pub(crate) fn traffic_class(&self) -> u8 {
+        match &self.ip {
+            IpHeader::Version4(ip, _) => ip.differentiated_services_code_point << 2,
+            IpHeader::Version6(ip, _) => ip.traffic_class & 0xfc,
+        }
+    }
+    // Builds a RST from `src` to `dst` that isn't tied to any stream.
+    pub(crate) fn tcp_reset(
+        src: SocketAddr,
+        dst: SocketAddr,
+        seq: u32,
+        ack: Option<u32>,
+    ) -> Result<NetworkPacket, IpStackError> {
+        let mut tcp_header = TcpHeader::new(src.port(), dst.port(), seq, 0);
+        tcp_header.rst = true;
+        if let Some(ack) = ack {
+            tcp_header.ack = true;
+            tcp_header.acknowledgment_number = ack;
+        }
+        let ip = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let ip_h =
+                    Ipv4Header::new(tcp_header.header_len(), TTL, 6, src.octets(), dst.octets());
+                tcp_header.checksum = tcp_header
+                    .calc_checksum_ipv4(&ip_h, &[])
+                    .map_err(|_| IpStackError::InvalidPacket)?;
+                IpHeader::Version4(ip_h, Ipv4Extensions::default())
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                let ip_h = Ipv6Header {
+                    traffic_class: 0,
+                    flow_label: 0,
+                    payload_length: tcp_header.header_len(),
+                    next_header: 6,
+                    hop_limit: TTL,
+                    source: src.octets(),
+                    destination: dst.octets(),
+                };
+                tcp_header.checksum = tcp_header
+                    .calc_checksum_ipv6(&ip_h, &[])
+                    .map_err(|_| IpStackError::InvalidPacket)?;
+                IpHeader::Version6(ip_h, Ipv6Extensions::default())
+            }
+            _ => return Err(IpStackError::InvalidPacket),
+        };
+        Ok(NetworkPacket {
+            ip,
+            transport: TransportHeader::Tcp(tcp_header),
+            payload: Vec::new(),
+        })
+    }
+    // Answers `original`, a packet refused by the stack, with the RST the
+    // peer would accept for it.
+    pub(crate) fn tcp_reset_for(original: &NetworkPacket) -> Result<NetworkPacket, IpStackError> {
+        let TransportHeader::Tcp(ref tcp) = original.transport else {
+            return Err(IpStackError::InvalidTcpPacket);
+        };
+        if tcp.rst {
+            return Err(IpStackError::InvalidTcpPacket);
+        }
+        let (seq, ack) = if tcp.ack {
+            (tcp.acknowledgment_number, None)
+        } else {
+            let len = original.payload.len() as u32 + tcp.syn as u32 + tcp.fin as u32;
+            (0, Some(tcp.sequence_number.wrapping_add(len)))
+        };
+        NetworkPacket::tcp_reset(original.dst_addr(), original.src_addr(), seq, ack)
+    }
+    // ICMP port unreachable for a refused datagram, quoting `raw`, its bytes
+    // as read from the device.
+    pub(crate) fn port_unreachable(
+        original: &NetworkPacket,
+        raw: &[u8],
+    ) -> Result<NetworkPacket, IpStackError> {
+        match original.ip {
+            IpHeader::Version4(ref header, _) => {
+                // RFC 792: the original IP header plus the first 8 bytes of its data.
+                let len = cmp::min(raw.len(), header.header_len() + 8);
+                let payload = raw[..len].to_vec();
+                let icmp = Icmpv4Header::with_checksum(
+                    Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port),
+                    &payload,
+                );
+                let ip_h = Ipv4Header::new(
+                    (icmp.header_len() + payload.len()) as u16,
+                    TTL,
+                    1,
+                    header.destination,
+                    header.source,
+                );
+                Ok(NetworkPacket {
+                    ip: IpHeader::Version4(ip_h, Ipv4Extensions::default()),
+                    transport: TransportHeader::Icmpv4(icmp),
+                    payload,
+                })
+            }
+            IpHeader::Version6(ref header, _) => {
+                // RFC 4443: as much of the original as fits in the minimum MTU.
+                let len = cmp::min(
+                    raw.len(),
+                    IPV6_MIN_MTU - Ipv6Header::SERIALIZED_SIZE - ICMPV6_HEADER_LEN,
+                );
+                let payload = raw[..len].to_vec();
+                let icmp = Icmpv6Header::with_checksum(
+                    Icmpv6Type::DestinationUnreachable(DestUnreachableCode::Port),
+                    header.destination,
+                    header.source,
+                    &payload,
+                )
+                .map_err(|_| IpStackError::InvalidPacket)?;
+                let ip_h = Ipv6Header {
+                    traffic_class: 0,
+                    flow_label: 0,
+                    payload_length: (icmp.header_len() + payload.len()) as u16,
+                    next_header: 58,
+                    hop_limit: TTL,
+                    source: header.destination,
+                    destination: header.source,
+                };
+                Ok(NetworkPacket {
+                    ip: IpHeader::Version6(ip_h, Ipv6Extensions::default()),
+                    transport: TransportHeader::Icmpv6(icmp),
+                    payload,
+                })
+            }
+        }
+    }
+}
+
+// The length the IP header claims, which must fit within what was read.
+// Anything after it is padding and not part of the packet.
+fn ip_total_length(buf: &[u8]) -> Result<usize, IpStackError> {
+    let len = match buf.first().map(|b| b >> 4) {
+        Some(4) if buf.len() >= 4 => u16::from_be_bytes([buf[2], buf[3]]) as usize,
+        Some(6) if buf.len() >= 6 => match u16::from_be_bytes([buf[4], buf[5]]) {
+            0 => return Ok(buf.len()), // jumbogram, see `jumbo_payload_length`
+            len => Ipv6Header::SERIALIZED_SIZE + len as usize,
+        },
+        _ => return Err(IpStackError::InvalidPacket),
+    };
+    if len > buf.len() {
+        return Err(IpStackError::TruncatedPacket);
+    }
+    Ok(len)
+}
+
+// Why `buf` failed to parse. Headers are bounds-checked as they're read, so
+// a TCP data offset past the segment is never followed; it's only told apart
+// from other malformed packets here.
+fn parse_error(buf: &[u8]) -> IpStackError {
+    const TCP: u8 = 6;
+    let Ok((_, TCP, segment)) = IpHeader::from_slice(buf) else {
+        return IpStackError::InvalidPacket;
+    };
+    match segment.get(12).map(|b| (b >> 4) as usize * 4) {
+        Some(len) if len < TCP_MINIMUM_HEADER_SIZE || len > segment.len() => {
+            IpStackError::InvalidTcpDataOffset
+        }
+        _ => IpStackError::InvalidPacket,
+    }
+}
+
+const IPV6_JUMBO_PAYLOAD: u8 = 0xc2;
+const IPV6_MIN_MTU: usize = 1280;
+const ICMPV6_HEADER_LEN: usize = 8;
+
+// RFC 2675: only read when the IPv6 payload length field is zero.
+fn jumbo_payload_length(ip: &IpHeader) -> Option<u32> {
+    let IpHeader::Version6(header, extensions) = ip else {
+        return None;
+    };
+    if header.payload_length != 0 {
+        return None;
+    }
+    let options = extensions.hop_by_hop_options.as_ref()?.payload();
+    let mut i = 0;
+    while i < options.len() {
+        // Pad1 is the only option without a length byte.
+        if options[i] == 0 {
+            i += 1;
+            continue;
+        }
+        let len = *options.get(i + 1)? as usize;
+        if options[i] == IPV6_JUMBO_PAYLOAD && len == 4 {
+            let value = options.get(i + 2..i + 6)?;
+            return Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]));
+        }
+        i += 2 + len;
+    }
+    None
+}
+
+const TCP_OPTION_EOL: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+const TCP_OPTION_TIMESTAMPS: u8 = 8;
+
+#[allow(dead_code)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub timestamps: Option<(u32, u32)>,
+}
+
+impl TcpOptions {
+    // Unknown kinds are skipped by their length byte; a length that runs past
+    // the option area, or doesn't fit a known kind, rejects the whole header.
+    pub(crate) fn parse(mut options: &[u8]) -> Result<TcpOptions, IpStackError> {
+        let mut parsed = TcpOptions::default();
+        while let Some(&kind) = options.first() {
+            match kind {
+                TCP_OPTION_EOL => break,
+                TCP_OPTION_NOP => {
+                    options = &options[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            let len = *options.get(1).ok_or(IpStackError::InvalidTcpPacket)? as usize;
+            if len < 2 || len > options.len() {
+                return Err(IpStackError::InvalidTcpPacket);
+            }
+            let value = &options[2..len];
+            match (kind, value.len()) {
+                (TCP_OPTION_MSS, 2) => parsed.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (TCP_OPTION_WINDOW_SCALE, 1) => parsed.window_scale = Some(value[0]),
+                (TCP_OPTION_SACK_PERMITTED, 0) => parsed.sack_permitted = true,
+                (TCP_OPTION_TIMESTAMPS, 8) => {
+                    parsed.timestamps = Some((
+                        u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                        u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+                    ))
+                }
+                (
+                    TCP_OPTION_MSS
+                    | TCP_OPTION_WINDOW_SCALE
+                    | TCP_OPTION_SACK_PERMITTED
+                    | TCP_OPTION_TIMESTAMPS,
+                    _,
+                ) => return Err(IpStackError::InvalidTcpPacket),
+                _ => {}
+            }
+            options = &options[len..];
+        }
+        Ok(parsed)
+    }
 }
 
 pub(super) struct TcpPacket {
@@ -126,6 +467,10 @@ impl TcpPacket {
     pub fn inner(&self) -> &TcpHeader {
         &self.header
     }
+    pub fn options(&self) -> TcpOptions {
+        // Malformed options are rejected by `NetworkPacket::parse`.
+        TcpOptions::parse(self.header.options()).unwrap_or_default()
+    }
     pub fn flags(&self) -> u8 {
         let inner = self.inner();
         let mut flags = 0;
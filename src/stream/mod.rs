@@ -1,12 +1,67 @@
-use std::net::SocketAddr;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    time::Duration,
+};
 
-pub use self::tcp::IpStackTcpStream;
-pub use self::udp::IpStackUdpStream;
+use tokio::time::Instant;
 
+use crate::packet::NetworkTuple;
+
+pub use self::tap::DuplexTap;
+pub use self::tcb::{TcbSnapshot, TcpState};
+pub use self::tcp::{ClosePolicy, IpStackTcpStream, ReservedBitsPolicy, TinygramPolicy};
+pub use self::udp::{IpStackUdpStream, UdpDirection, UdpOverflowPolicy, UdpPayloadTransform};
+
+mod tap;
 mod tcb;
 mod tcp;
 mod udp;
 
+// State shared between a stream and the stack's record of its flow.
+#[derive(Default)]
+pub(crate) struct FlowShared {
+    // Set once the stream is first polled.
+    pub(crate) polled: AtomicBool,
+    // Set once the stack has dropped the flow on its own, for never being
+    // polled or by a reset of every flow.
+    pub(crate) reaped: AtomicBool,
+    // Set while a TCP flow is established.
+    pub(crate) established: AtomicBool,
+}
+
+// Unpredictable enough to keep flows from being correlated, not for crypto.
+pub(crate) fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+// `duration` stretched by a random part of `jitter`, so flows started
+// together don't keep firing their timers together.
+pub(crate) fn jittered(duration: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return duration;
+    }
+    duration + jitter.mul_f64(random_u32() as f64 / u32::MAX as f64)
+}
+
+// Next IPv4 identification of a flow, counting up from a random start when
+// ids are randomized and always 0 otherwise.
+pub(crate) struct IpId(Option<AtomicU16>);
+
+impl IpId {
+    pub(crate) fn new(randomize: bool) -> IpId {
+        IpId(randomize.then(|| AtomicU16::new(random_u32() as u16)))
+    }
+    pub(crate) fn next(&self) -> u16 {
+        self.0
+            .as_ref()
+            .map_or(0, |id| id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 pub enum IpStackStream {
     Tcp(IpStackTcpStream),
     Udp(IpStackUdpStream),
@@ -25,4 +80,28 @@ impl IpStackStream {
             IpStackStream::Udp(udp) => udp.peer_addr(),
         }
     }
+    pub fn network_tuple(&self) -> NetworkTuple {
+        match self {
+            IpStackStream::Tcp(tcp) => tcp.network_tuple(),
+            IpStackStream::Udp(udp) => udp.network_tuple(),
+        }
+    }
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        match self {
+            IpStackStream::Tcp(tcp) => tcp.set_deadline(deadline),
+            IpStackStream::Udp(udp) => udp.set_deadline(deadline),
+        }
+    }
+    pub fn set_ttl(&mut self, ttl: u8) -> std::io::Result<()> {
+        match self {
+            IpStackStream::Tcp(tcp) => tcp.set_ttl(ttl),
+            IpStackStream::Udp(udp) => udp.set_ttl(ttl),
+        }
+    }
+    pub fn set_traffic_class(&mut self, traffic_class: u8) {
+        match self {
+            IpStackStream::Tcp(tcp) => tcp.set_traffic_class(traffic_class),
+            IpStackStream::Udp(udp) => udp.set_traffic_class(traffic_class),
+        }
+    }
 }
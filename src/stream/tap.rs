@@ -0,0 +1,57 @@
+use std::{
+    cmp, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+const TAP_CAPACITY: usize = 64; // chunks
+
+/// Read-only copy of one direction of a TCP flow's byte stream.
+///
+/// The flow never waits on a tap: chunks are dropped while it is full, so a
+/// reader that falls behind sees gaps. Reaches EOF once the stream is dropped.
+pub struct DuplexTap {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl DuplexTap {
+    pub(super) fn new() -> (Sender<Vec<u8>>, DuplexTap) {
+        let (sender, receiver) = mpsc::channel(TAP_CAPACITY);
+        let tap = DuplexTap {
+            receiver,
+            chunk: Vec::new(),
+            offset: 0,
+        };
+        (sender, tap)
+    }
+}
+
+impl AsyncRead for DuplexTap {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.offset == self.chunk.len() {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = cmp::min(buf.remaining(), self.chunk.len() - self.offset);
+        buf.put_slice(&self.chunk[self.offset..self.offset + len]);
+        self.offset += len;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,22 +1,36 @@
 use std::{
+    cmp,
     collections::BTreeMap,
+    future::Future,
     pin::Pin,
+    sync::{atomic::Ordering, Arc},
+    task::Context,
     time::{Duration, SystemTime},
 };
 
-use tokio::time::Sleep;
+use tokio::time::{Instant, Sleep};
 
-use crate::packet::TcpPacket;
+use crate::{
+    arena::Arena,
+    packet::{NetworkTuple, TcpPacket},
+    stream::{jittered, random_u32, FlowShared, TinygramPolicy},
+    IpStackConfig, StateChangeCallback,
+};
 
-const MAX_UNACK: u32 = 1024 * 16; // 16KB
-const READ_BUFFER_SIZE: usize = 1024 * 16; // 16KB
+pub(super) const READ_BUFFER_SIZE: usize = 1024 * 16; // 16KB
+const DUP_ACK_THRESHOLD: u32 = 3;
+pub(super) const MAX_WINDOW_SCALE: u8 = 14; // RFC 7323
+const MAX_RTO: Duration = Duration::from_secs(60);
 
+/// State of a TCP flow's connection.
 #[derive(Clone, Debug, PartialEq, Eq, Copy)]
 pub enum TcpState {
     SynReceived(bool), // bool means if syn/ack is sent
     Established,
-    FinWait1,
-    FinWait2(bool), // bool means waiting for ack
+    CloseWait, // the peer has sent FIN, we haven't yet
+    FinWait1,  // we've sent FIN, the peer hasn't acknowledged it
+    FinWait2,  // our FIN is acknowledged, the peer's hasn't arrived
+    LastAck,   // both FINs sent, ours isn't acknowledged yet; also stands for CLOSING
     Closed,
 }
 #[derive(Clone, Debug)]
@@ -29,6 +43,33 @@ pub(super) enum PacketStatus {
     KeepAlive,
 }
 
+/// A point-in-time copy of a TCP flow's control block, for debugging.
+#[derive(Clone, Debug)]
+pub struct TcbSnapshot {
+    pub state: TcpState,
+    /// Oldest unacknowledged sequence number.
+    pub snd_una: u32,
+    /// Next sequence number to be sent.
+    pub snd_nxt: u32,
+    /// Next sequence number expected from the peer.
+    pub rcv_nxt: u32,
+    /// The peer's window, scaled.
+    pub snd_wnd: u32,
+    /// Our window, unscaled.
+    pub rcv_wnd: u16,
+    /// (send, recv) shifts, once negotiated.
+    pub window_scale: Option<(u8, u8)>,
+    pub inflight_segments: usize,
+    pub out_of_order_segments: usize,
+    pub retransmit_count: u32,
+    /// Time left on the handshake timer.
+    pub timeout: Duration,
+    /// Current retransmission timeout, backoff included.
+    pub rto: Duration,
+    /// Time left on the retransmission timer, while it runs.
+    pub rto_remaining: Option<Duration>,
+}
+
 pub(super) struct Tcb {
     pub(super) seq: u32,
     pub(super) retransmission: Option<u32>,
@@ -37,16 +78,43 @@ pub(super) struct Tcb {
     pub(super) timeout: Pin<Box<Sleep>>,
     pub tcp_timeout: Duration,
     recv_window: u16,
-    pub(super) send_window: u16,
+    pub(super) send_window: u32,
     state: TcpState,
     pub(super) avg_send_window: (u64, u64),
     pub(super) inflight_packets: Vec<InflightPacket>,
     pub(super) unordered_packets: BTreeMap<u32, UnorderedPacket>,
+    send_buffer_high_watermark: u32,
+    send_buffer_low_watermark: u32,
+    send_buffer_blocked: bool,
+    max_unordered_packets: usize,
+    dup_ack_count: u32,
+    window_scale: Option<(u8, u8)>, // (send, recv) shifts, once negotiated
+    retransmit_count: u32,
+    ts_base: Instant,
+    ts_offset: u32,
+    ts_recent: Option<u32>, // the peer's latest TSval, once negotiated
+    initial_rto: Duration,
+    rto: Duration,
+    rto_timer: Option<Pin<Box<Sleep>>>, // runs while anything sent is unacknowledged
+    timer_jitter: Duration,
+    fin_seq: Option<u32>,  // where the peer's FIN sits, once it fits the window
+    sent_fin: Option<u32>, // where our FIN sits, until it's acknowledged
+    coalesce_below: usize, // segments shorter than this join the one before
+    arena: Arc<Arena>,
+    shared: Arc<FlowShared>,
+    on_state_change: Option<(NetworkTuple, StateChangeCallback)>,
 }
 
 impl Tcb {
-    pub(super) fn new(ack: u32, tcp_timeout: Duration) -> Tcb {
+    pub(super) fn new(
+        ack: u32,
+        tuple: NetworkTuple,
+        config: &IpStackConfig,
+        arena: Arc<Arena>,
+        shared: Arc<FlowShared>,
+    ) -> Tcb {
         let seq = 100;
+        let tcp_timeout = config.tcp_timeout;
         Tcb {
             seq,
             retransmission: None,
@@ -56,31 +124,190 @@ impl Tcb {
             timeout: Box::pin(tokio::time::sleep_until(
                 tokio::time::Instant::now() + tcp_timeout,
             )),
-            send_window: u16::MAX,
+            send_window: u16::MAX as u32,
             recv_window: 0,
             state: TcpState::SynReceived(false),
             avg_send_window: (1, 1),
             inflight_packets: Vec::new(),
             unordered_packets: BTreeMap::new(),
+            send_buffer_high_watermark: config.send_buffer_high_watermark,
+            send_buffer_low_watermark: cmp::min(
+                config.send_buffer_low_watermark,
+                config.send_buffer_high_watermark,
+            ),
+            send_buffer_blocked: false,
+            max_unordered_packets: config.max_out_of_order_segments,
+            dup_ack_count: 0,
+            window_scale: None,
+            retransmit_count: 0,
+            coalesce_below: match config.tinygram_policy {
+                TinygramPolicy::Coalesce => config.min_segment_size,
+                TinygramPolicy::Drop => 0,
+            },
+            ts_base: Instant::now(),
+            ts_offset: if config.randomize_flow_ids {
+                random_u32()
+            } else {
+                0
+            },
+            ts_recent: None,
+            initial_rto: config.initial_rto,
+            rto: config.initial_rto,
+            rto_timer: None,
+            timer_jitter: config.timer_jitter,
+            fin_seq: None,
+            sent_fin: None,
+            arena,
+            shared,
+            on_state_change: config.on_state_change.clone().map(|f| (tuple, f)),
         }
     }
     pub(super) fn add_inflight_packet(&mut self, seq: u32, buf: &[u8]) {
         self.inflight_packets
             .push(InflightPacket::new(seq, buf.to_vec()));
         self.seq = self.seq.wrapping_add(buf.len() as u32);
+        // RFC 6298 (5.1): sending data starts the timer unless it's running.
+        if self.rto_timer.is_none() {
+            self.restart_rto();
+        }
     }
-    pub(super) fn add_unordered_packet(&mut self, seq: u32, buf: &[u8]) {
-        if seq < self.ack {
-            return;
+    fn restart_rto(&mut self) {
+        let timer = tokio::time::sleep(jittered(self.rto, self.timer_jitter));
+        self.rto_timer = Some(Box::pin(timer));
+    }
+    // Whether the retransmission timer expired, in which case it's restarted
+    // with the timeout doubled (RFC 6298 (5.5), (5.6)). It keeps running
+    // after our FIN for as long as anything is left unacknowledged.
+    pub(super) fn poll_rto(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.state == TcpState::Closed || !self.is_outstanding() {
+            self.rto_timer = None;
+            return false;
+        }
+        let Some(timer) = self.rto_timer.as_mut() else {
+            return false;
+        };
+        if timer.as_mut().poll(cx).is_pending() {
+            return false;
+        }
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+        self.restart_rto();
+        if let Some(timer) = self.rto_timer.as_mut() {
+            _ = timer.as_mut().poll(cx);
+        }
+        true
+    }
+    // Returns whether all of `buf` was kept.
+    pub(super) fn add_unordered_packet(&mut self, seq: u32, buf: &[u8]) -> bool {
+        let offset = seq.wrapping_sub(self.ack);
+        if (offset as i32) < 0 || offset as usize >= READ_BUFFER_SIZE {
+            return false;
+        }
+        let offset = offset as usize;
+        let len = cmp::min(buf.len(), READ_BUFFER_SIZE - offset);
+        // Data already buffered wins over data overlapping it, so segments
+        // never overlap, and together they never hold more than the window.
+        let mut kept = len == buf.len();
+        for (start, end) in self.uncovered(offset, offset + len) {
+            let seq = self.ack.wrapping_add(start as u32);
+            kept &= self.add_segment(seq, start, &buf[start - offset..end - offset]);
+        }
+        kept
+    }
+    // Parts of `start..end`, as offsets from `ack`, no buffered segment covers.
+    fn uncovered(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let mut held: Vec<(usize, usize)> = self
+            .unordered_packets
+            .iter()
+            .map(|(&seq, p)| {
+                let offset = seq.wrapping_sub(self.ack) as usize;
+                (offset, offset + p.payload.len())
+            })
+            .collect();
+        held.sort_unstable();
+        let mut gaps = Vec::new();
+        let mut from = start;
+        for (held_start, held_end) in held {
+            if held_start >= end {
+                break;
+            }
+            if held_start > from {
+                gaps.push((from, held_start));
+            }
+            from = cmp::max(from, held_end);
+        }
+        if from < end {
+            gaps.push((from, end));
+        }
+        gaps
+    }
+    // Buffers data no other segment covers, `offset` past `ack`.
+    fn add_segment(&mut self, seq: u32, offset: usize, buf: &[u8]) -> bool {
+        if buf.len() < self.coalesce_below && self.coalesce(seq, buf) {
+            return true;
+        }
+        // Segments past the contiguous point are capped in count, so a hole
+        // can't be used to pin a buffer per byte of the window.
+        if offset != 0 && self.unordered_packets.len() >= self.max_unordered_packets {
+            return false;
+        }
+        // Past the shared budget the segment is left for the peer to resend,
+        // unless it's the next one expected: segments held beyond it are
+        // given up first, so a full budget can't stall the flow.
+        while !self.arena.reserve(buf.len()) {
+            if offset != 0 {
+                return false;
+            }
+            let Some((_, last)) = self.unordered_packets.pop_last() else {
+                return false;
+            };
+            self.arena.release(last.payload.len());
         }
         self.unordered_packets
             .insert(seq, UnorderedPacket::new(buf.to_vec()));
+        true
     }
+    // Appends `buf` to the buffered segment ending right where it starts.
+    fn coalesce(&mut self, seq: u32, buf: &[u8]) -> bool {
+        let Some((&start, prev)) = self.unordered_packets.range_mut(..seq).next_back() else {
+            return false;
+        };
+        if start.wrapping_add(prev.payload.len() as u32) != seq || !self.arena.reserve(buf.len()) {
+            return false;
+        }
+        prev.payload.extend_from_slice(buf);
+        true
+    }
+    // The FIN follows the segment's data, so it only counts once all of that
+    // data fits the window; it is consumed by `take_fin` when it's next.
+    pub(super) fn add_fin(&mut self, seq: u32, buf: &[u8]) {
+        if buf.is_empty() {
+            let offset = seq.wrapping_sub(self.ack);
+            if (offset as i32) < 0 || offset as usize >= READ_BUFFER_SIZE {
+                return;
+            }
+        } else if !self.add_unordered_packet(seq, buf) {
+            return;
+        }
+        self.fin_seq = Some(seq.wrapping_add(buf.len() as u32));
+    }
+    pub(super) fn take_fin(&mut self) -> bool {
+        if self.fin_seq != Some(self.ack) {
+            return false;
+        }
+        self.fin_seq = None;
+        self.add_ack(1);
+        true
+    }
+    pub(super) fn get_read_buffer_len(&self) -> usize {
+        self.unordered_packets
+            .iter()
+            .fold(0, |acc, (_, p)| acc + p.payload.len())
+    }
+    // The window shrinks as the shared budget runs out.
     pub(super) fn get_available_read_buffer_size(&self) -> usize {
-        READ_BUFFER_SIZE.saturating_sub(
-            self.unordered_packets
-                .iter()
-                .fold(0, |acc, (_, p)| acc + p.payload.len()),
+        cmp::min(
+            READ_BUFFER_SIZE.saturating_sub(self.get_read_buffer_len()),
+            self.arena.available(),
         )
     }
     pub(super) fn get_unordered_packets(&mut self) -> Option<Vec<u8>> {
@@ -88,13 +315,28 @@ impl Tcb {
         // for (seq,_) in self.unordered_packets.iter() {
         //     dbg!(seq);
         // }
-        self.unordered_packets
-            .remove(&self.ack)
-            .map(|p| p.payload.clone())
+        let packet = self.unordered_packets.remove(&self.ack)?;
+        self.arena.release(packet.payload.len());
+        Some(packet.payload)
     }
     pub(super) fn add_seq_one(&mut self) {
         self.seq = self.seq.wrapping_add(1);
     }
+    // Our FIN takes a sequence number and, like data, is retransmitted until
+    // the peer acknowledges it.
+    pub(super) fn add_sent_fin(&mut self) {
+        self.sent_fin = Some(self.seq);
+        self.add_seq_one();
+        if self.rto_timer.is_none() {
+            self.restart_rto();
+        }
+    }
+    pub(super) fn get_sent_fin(&self) -> Option<u32> {
+        self.sent_fin
+    }
+    fn is_outstanding(&self) -> bool {
+        !self.inflight_packets.is_empty() || self.sent_fin.is_some()
+    }
     pub(super) fn get_seq(&self) -> u32 {
         self.seq
     }
@@ -105,26 +347,79 @@ impl Tcb {
         self.ack
     }
     pub(super) fn change_state(&mut self, state: TcpState) {
+        // Sending the SYN-ACK only flips a flag within SYN-RECEIVED, which
+        // isn't a transition.
+        let transition = state != self.state
+            && !matches!(
+                (self.state, state),
+                (TcpState::SynReceived(_), TcpState::SynReceived(_))
+            );
+        if let Some((tuple, ref on_state_change)) = self.on_state_change {
+            if transition {
+                on_state_change(tuple, self.state, state);
+            }
+        }
         self.state = state;
+        self.shared
+            .established
+            .store(state == TcpState::Established, Ordering::Relaxed);
     }
     pub(super) fn get_state(&self) -> &TcpState {
         &self.state
     }
+    pub(super) fn set_window_scale(&mut self, send: u8, recv: u8) {
+        self.window_scale = Some((
+            cmp::min(send, MAX_WINDOW_SCALE),
+            cmp::min(recv, MAX_WINDOW_SCALE),
+        ));
+    }
+    pub(super) fn get_window_scale(&self) -> Option<(u8, u8)> {
+        self.window_scale
+    }
+    pub(super) fn enable_timestamps(&mut self, ts_recent: u32) {
+        self.ts_recent = Some(ts_recent);
+    }
+    pub(super) fn update_ts_recent(&mut self, ts_val: u32) {
+        if let Some(ref mut recent) = self.ts_recent {
+            *recent = ts_val;
+        }
+    }
+    // (TSval, TSecr) for the next segment, with TSval counting milliseconds.
+    pub(super) fn get_timestamps(&self) -> Option<(u32, u32)> {
+        self.ts_recent.map(|recent| {
+            let elapsed = self.ts_base.elapsed().as_millis() as u32;
+            (self.ts_offset.wrapping_add(elapsed), recent)
+        })
+    }
+    fn scale_send_window(&self, window: u16) -> u32 {
+        let shift = self.window_scale.map_or(0, |(send, _)| send);
+        (window as u32) << shift
+    }
     pub(super) fn change_send_window(&mut self, window: u16) {
+        let window = self.scale_send_window(window);
         let avg_send_window = ((self.avg_send_window.0 * self.avg_send_window.1) + window as u64)
             / (self.avg_send_window.1 + 1);
         self.avg_send_window.0 = avg_send_window;
         self.avg_send_window.1 += 1;
         self.send_window = window;
     }
-    pub(super) fn get_send_window(&self) -> u16 {
+    pub(super) fn get_send_window(&self) -> u32 {
+        self.send_window
+    }
+    // What the peer's window still has room for beyond the unacknowledged data.
+    pub(super) fn get_usable_window(&self) -> u32 {
         self.send_window
+            .saturating_sub(self.seq.wrapping_sub(self.last_ack))
     }
     pub(super) fn change_recv_window(&mut self, window: u16) {
         self.recv_window = window;
     }
-    pub(super) fn get_recv_window(&self) -> u16 {
-        self.recv_window
+    // The window field of a SYN is never scaled.
+    pub(super) fn get_recv_window(&self, syn: bool) -> u16 {
+        match self.window_scale {
+            Some((_, recv)) if !syn => self.recv_window >> recv,
+            _ => self.recv_window,
+        }
     }
     // #[inline(always)]
     // pub(super) fn buffer_size(&self, payload_len: u16) -> u16 {
@@ -154,14 +449,17 @@ impl Tcb {
         {
             PacketStatus::Invalid
         } else if self.last_ack == incoming_packet.inner().acknowledgment_number {
-            if !p.is_empty() {
+            // A keepalive probe sits one byte below the expected sequence and
+            // may carry a single garbage byte, which must not be delivered.
+            if self.ack.wrapping_sub(1) == incoming_packet.inner().sequence_number && p.len() <= 1 {
+                PacketStatus::KeepAlive
+            } else if !p.is_empty() {
                 PacketStatus::NewPacket
-            } else if self.send_window == incoming_packet.inner().window_size
+            } else if self.send_window
+                == self.scale_send_window(incoming_packet.inner().window_size)
                 && self.seq != self.last_ack
             {
                 PacketStatus::RetransmissionRequest
-            } else if self.ack.wrapping_sub(1) == incoming_packet.inner().sequence_number {
-                PacketStatus::KeepAlive
             } else {
                 PacketStatus::WindowUpdate
             }
@@ -175,34 +473,117 @@ impl Tcb {
             PacketStatus::Invalid
         }
     }
+    // Acks data that was never sent.
+    pub(super) fn is_future_ack(&self, ack: u32) -> bool {
+        (ack.wrapping_sub(self.seq) as i32) > 0
+    }
     pub(super) fn change_last_ack(&mut self, ack: u32) {
         self.timeout
             .as_mut()
             .reset(tokio::time::Instant::now() + self.tcp_timeout);
         let distance = ack.wrapping_sub(self.last_ack);
+        if distance != 0 {
+            self.dup_ack_count = 0;
+        }
 
-        if matches!(self.state, TcpState::Established) {
-            if let Some(i) = self.inflight_packets.iter().position(|p| p.contains(ack)) {
-                let mut inflight_packet = self.inflight_packets.remove(i);
-                let distance = ack.wrapping_sub(inflight_packet.seq);
-                if (distance as usize) < inflight_packet.payload.len() {
-                    inflight_packet.payload.drain(0..distance as usize);
-                    inflight_packet.seq = ack;
-                    self.inflight_packets.push(inflight_packet);
+        if matches!(
+            self.state,
+            TcpState::Established
+                | TcpState::CloseWait
+                | TcpState::FinWait1
+                | TcpState::FinWait2
+                | TcpState::LastAck
+        ) {
+            // Drop every segment the ACK covers and trim the one it splits.
+            self.inflight_packets.retain_mut(|p| {
+                let acked = ack.wrapping_sub(p.seq);
+                if (acked as i32) <= 0 {
+                    return true;
+                }
+                if acked as usize >= p.payload.len() {
+                    return false;
+                }
+                p.payload.drain(0..acked as usize);
+                p.seq = ack;
+                true
+            });
+            if self
+                .sent_fin
+                .is_some_and(|fin| (ack.wrapping_sub(fin) as i32) > 0)
+            {
+                self.sent_fin = None;
+            }
+            // RFC 6298 (5.2), (5.3): an ACK of new data stops the timer once
+            // nothing is outstanding, and restarts it otherwise.
+            if distance != 0 {
+                self.rto = self.initial_rto;
+                if !self.is_outstanding() {
+                    self.rto_timer = None;
+                } else {
+                    self.restart_rto();
                 }
             }
         }
 
         self.last_ack = self.last_ack.wrapping_add(distance);
     }
-    pub fn is_send_buffer_full(&self) -> bool {
-        self.seq.wrapping_sub(self.last_ack) >= MAX_UNACK
+    pub(super) fn snapshot(&self) -> TcbSnapshot {
+        TcbSnapshot {
+            state: self.state,
+            snd_una: self.last_ack,
+            snd_nxt: self.seq,
+            rcv_nxt: self.ack,
+            snd_wnd: self.send_window,
+            rcv_wnd: self.recv_window,
+            window_scale: self.window_scale,
+            inflight_segments: self.inflight_packets.len(),
+            out_of_order_segments: self.unordered_packets.len(),
+            retransmit_count: self.retransmit_count,
+            timeout: self
+                .timeout
+                .deadline()
+                .saturating_duration_since(Instant::now()),
+            rto: self.rto,
+            rto_remaining: self
+                .rto_timer
+                .as_ref()
+                .map(|timer| timer.deadline().saturating_duration_since(Instant::now())),
+        }
+    }
+    pub(super) fn add_retransmit(&mut self) {
+        self.retransmit_count = self.retransmit_count.wrapping_add(1);
+    }
+    pub(super) fn get_retransmit_count(&self) -> u32 {
+        self.retransmit_count
+    }
+    // Fast retransmit: the third duplicate ACK signals a lost segment.
+    pub(super) fn add_dup_ack(&mut self) -> bool {
+        self.dup_ack_count += 1;
+        self.dup_ack_count == DUP_ACK_THRESHOLD
+    }
+    // Once the high watermark is reached, the buffer stays full until the
+    // peer has acknowledged enough data to drain it below the low watermark.
+    pub fn is_send_buffer_full(&mut self) -> bool {
+        let unacked = self.seq.wrapping_sub(self.last_ack);
+        if self.send_buffer_blocked {
+            self.send_buffer_blocked = unacked > self.send_buffer_low_watermark;
+        } else {
+            self.send_buffer_blocked = unacked >= self.send_buffer_high_watermark;
+        }
+        self.send_buffer_blocked
+    }
+}
+
+impl Drop for Tcb {
+    fn drop(&mut self) {
+        self.arena.release(self.get_read_buffer_len());
     }
 }
 
 pub struct InflightPacket {
     pub seq: u32,
     pub payload: Vec<u8>,
+    #[allow(dead_code)]
     pub send_time: SystemTime,
 }
 
@@ -214,13 +595,11 @@ impl InflightPacket {
             send_time: SystemTime::now(),
         }
     }
-    pub(crate) fn contains(&self, seq: u32) -> bool {
-        self.seq < seq && self.seq + self.payload.len() as u32 >= seq
-    }
 }
 
 pub struct UnorderedPacket {
     pub payload: Vec<u8>,
+    #[allow(dead_code)]
     pub recv_time: SystemTime,
 }
 
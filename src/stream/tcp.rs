@@ -1,26 +1,84 @@
 use crate::{
+    arena::Arena,
     error::IpStackError,
     packet::{tcp_flags, IpStackPacketProtocol, TcpPacket},
-    stream::tcb::{Tcb, TcpState},
-    DROP_TTL, TTL,
+    stream::{
+        jittered,
+        tap::DuplexTap,
+        tcb::{Tcb, TcbSnapshot, TcpState, READ_BUFFER_SIZE},
+        FlowShared, IpId,
+    },
+    CloseReason, IpStackConfig, DROP_TTL, TTL,
 };
-use etherparse::{Ipv4Extensions, Ipv4Header, Ipv6Extensions, TransportHeader};
+use etherparse::{Ipv4Extensions, Ipv4Header, Ipv6Extensions, TcpOptionElement, TransportHeader};
 use std::{
-    cmp, fmt::Display, future::Future, io::{Error, ErrorKind}, net::SocketAddr, pin::Pin, task::Waker, time::Duration
+    cmp, fmt::Display, future::Future, io::{Error, ErrorKind}, net::SocketAddr, pin::Pin, sync::{atomic::Ordering, Arc}, task::{Context, Waker}, time::Duration
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-        Notify,
-    },
+    sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+    time::{Instant, Sleep},
 };
-use tracing::{trace, warn};
+use tracing::{trace, Level};
 
-use crate::packet::NetworkPacket;
+use crate::packet::{NetworkPacket, NetworkTuple};
 
 use super::tcb::PacketStatus;
 
+// Emits an event for one flow, if that flow's log level lets it through.
+macro_rules! flow_event {
+    ($stream:expr, $level:expr, $($arg:tt)+) => {
+        if $stream.log_level >= $level {
+            tracing::event!($level, flow = %$stream.network_tuple(), $($arg)+)
+        }
+    };
+}
+
+const IPV4_HEADER_LEN: u16 = 20;
+const IPV6_HEADER_LEN: u16 = 40;
+const TCP_HEADER_LEN: u16 = 20;
+const TIMESTAMPS_OPTION_LEN: u16 = 12; // two NOPs and the option itself
+
+// Assumed for peers whose SYN has no MSS option (RFC 9293 section 3.7.1).
+const DEFAULT_IPV4_MSS: u16 = 536;
+const DEFAULT_IPV6_MSS: u16 = 1220;
+
+/// How a TCP flow is closed once its deadline passes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClosePolicy {
+    /// Send a FIN and let the peer finish the close.
+    #[default]
+    Graceful,
+    /// Send a RST and drop the flow immediately.
+    Reset,
+}
+
+/// What happens to a TCP segment carrying less than
+/// [`IpStackConfig::min_segment_size`] bytes of data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TinygramPolicy {
+    /// Hand it out, and acknowledge it, in one read together with the
+    /// tinygrams queued right behind it.
+    #[default]
+    Coalesce,
+    /// Drop it while more segments are queued behind it, leaving the peer to
+    /// resend it. One arriving on its own, as interactive input does, is kept.
+    Drop,
+}
+
+/// What happens to a TCP segment with any of the reserved header bits set,
+/// the retired NS bit included. The ECN bits, CWR and ECE, aren't reserved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReservedBitsPolicy {
+    /// Handle it like any other segment.
+    #[default]
+    Pass,
+    /// Clear the bits, then handle it like any other segment.
+    Ignore,
+    /// Drop it.
+    Drop,
+}
+
 pub struct IpStackTcpStream {
     src_addr: SocketAddr,
     dst_addr: SocketAddr,
@@ -30,8 +88,52 @@ pub struct IpStackTcpStream {
     packet_to_send: Option<NetworkPacket>,
     tcb: Tcb,
     mtu: u16,
-    shutdown: Option<Notify>,
     write_notify: Option<Waker>,
+    ingress_tap: Option<Sender<Vec<u8>>>,
+    egress_tap: Option<Sender<Vec<u8>>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    deadline_policy: ClosePolicy,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    close_reason: Option<CloseReason>,
+    removed: bool,
+    read_stash: Vec<u8>,
+    max_write_segments: usize,
+    peer_mss: u16,
+    shared: Arc<FlowShared>,
+    ip_id: IpId,
+    ttl: u8,
+    traffic_class: u8,
+    persist: Option<(Pin<Box<Sleep>>, Duration)>,
+    persist_interval: Duration,
+    persist_max_interval: Duration,
+    probe_count: u32,
+    timer_jitter: Duration,
+    quota: Option<u64>,
+    quota_policy: ClosePolicy,
+    transferred: u64,
+    initial_recv_window: Option<u16>,
+    min_segment_size: usize,
+    tinygram_policy: TinygramPolicy,
+    tinygrams_dropped: u64,
+    syn_received: Instant,
+    handshake_rtt: Option<Duration>,
+    log_level: Level,
+}
+
+fn tcp_checksum(
+    ip_header: &etherparse::IpHeader,
+    tcp_header: &etherparse::TcpHeader,
+    payload: &[u8],
+) -> Result<u16, Error> {
+    match ip_header {
+        etherparse::IpHeader::Version4(ip_header, _) => {
+            tcp_header.calc_checksum_ipv4(ip_header, payload)
+        }
+        etherparse::IpHeader::Version6(ip_header, _) => {
+            tcp_header.calc_checksum_ipv6(ip_header, payload)
+        }
+    }
+    .map_err(|_e| Error::from(ErrorKind::InvalidInput))
 }
 
 impl Display for IpStackTcpStream {
@@ -46,10 +148,26 @@ impl IpStackTcpStream {
         dst_addr: SocketAddr,
         tcp: TcpPacket,
         pkt_sender: UnboundedSender<NetworkPacket>,
-        mtu: u16,
-        tcp_timeout: Duration,
+        config: &IpStackConfig,
+        arena: Arc<Arena>,
     ) -> Result<IpStackTcpStream, IpStackError> {
         let (stream_sender, stream_receiver) = mpsc::unbounded_channel::<NetworkPacket>();
+        let shared = Arc::<FlowShared>::default();
+        let mut tcb = Tcb::new(
+            tcp.inner().sequence_number + 1,
+            NetworkTuple::new(src_addr, dst_addr, true),
+            config,
+            arena,
+            shared.clone(),
+        );
+        let options = tcp.options();
+        // Scaling is only in effect when both SYNs carry the option.
+        if let (Some(send), Some(recv)) = (options.window_scale, config.window_scale) {
+            tcb.set_window_scale(send, recv);
+        }
+        if let Some((ts_val, _)) = options.timestamps.filter(|_| config.tcp_timestamps) {
+            tcb.enable_timestamps(ts_val);
+        }
 
         let mut stream = IpStackTcpStream {
             src_addr,
@@ -58,16 +176,50 @@ impl IpStackTcpStream {
             stream_receiver,
             packet_sender: pkt_sender.clone(),
             packet_to_send: None,
-            tcb: Tcb::new(tcp.inner().sequence_number + 1, tcp_timeout),
-            mtu,
-            shutdown: None,
+            tcb,
+            mtu: config.mtu,
             write_notify: None,
+            ingress_tap: None,
+            egress_tap: None,
+            deadline: None,
+            deadline_policy: config.deadline_policy,
+            write_deadline: None,
+            close_reason: None,
+            removed: false,
+            read_stash: Vec::new(),
+            max_write_segments: cmp::max(config.max_write_segments, 1),
+            peer_mss: options.mss.unwrap_or(match src_addr {
+                SocketAddr::V4(_) => DEFAULT_IPV4_MSS,
+                SocketAddr::V6(_) => DEFAULT_IPV6_MSS,
+            }),
+            shared,
+            ip_id: IpId::new(config.randomize_flow_ids),
+            ttl: TTL,
+            traffic_class: 0,
+            persist: None,
+            persist_interval: config.persist_interval,
+            persist_max_interval: cmp::max(config.persist_max_interval, config.persist_interval),
+            probe_count: 0,
+            timer_jitter: config.timer_jitter,
+            quota: config.flow_quota,
+            quota_policy: config.quota_policy,
+            transferred: 0,
+            initial_recv_window: config.initial_recv_window,
+            min_segment_size: config.min_segment_size,
+            tinygram_policy: config.tinygram_policy,
+            tinygrams_dropped: 0,
+            syn_received: Instant::now(),
+            handshake_rtt: None,
+            log_level: Level::INFO,
         };
+        if let Some(ref flow_log_level) = config.flow_log_level {
+            stream.log_level = flow_log_level(stream.network_tuple());
+        }
         if !tcp.inner().syn {
             pkt_sender
                 .send(stream.create_rev_packet(
                     tcp_flags::RST | tcp_flags::ACK,
-                    TTL,
+                    stream.ttl,
                     None,
                     Vec::new(),
                 )?)
@@ -79,11 +231,31 @@ impl IpStackTcpStream {
     pub(crate) fn stream_sender(&self) -> UnboundedSender<NetworkPacket> {
         self.stream_sender.clone()
     }
+    pub(crate) fn shared(&self) -> Arc<FlowShared> {
+        self.shared.clone()
+    }
+    fn max_payload_len(&self, ip_header_size: u16, tcp_header_size: u16) -> u16 {
+        let max = self.mtu.saturating_sub(ip_header_size + tcp_header_size);
+        // The peer's MSS excludes options, which come out of it as well.
+        cmp::min(
+            max,
+            self.peer_mss
+                .saturating_sub(tcp_header_size - TCP_HEADER_LEN),
+        )
+    }
     fn calculate_payload_len(&self, ip_header_size: u16, tcp_header_size: u16) -> u16 {
         cmp::min(
             self.tcb.get_send_window(),
-            self.mtu.saturating_sub(ip_header_size + tcp_header_size),
-        )
+            self.max_payload_len(ip_header_size, tcp_header_size) as u32,
+        ) as u16
+    }
+    // Asks the stack to forget this flow. The first reason recorded wins.
+    fn create_drop_packet(&mut self, reason: CloseReason) -> Result<NetworkPacket, Error> {
+        let reason = *self.close_reason.get_or_insert(reason);
+        let mut packet = self.create_rev_packet(0, DROP_TTL, None, Vec::new())?;
+        packet.payload = vec![reason as u8];
+        self.removed = true;
+        Ok(packet)
     }
     fn create_rev_packet(
         &self,
@@ -96,11 +268,27 @@ impl IpStackTcpStream {
             self.dst_addr.port(),
             self.src_addr.port(),
             seq.unwrap_or(self.tcb.get_seq()),
-            self.tcb.get_recv_window(),
+            self.tcb.get_recv_window(flags & tcp_flags::SYN != 0),
         );
         tcp_header.acknowledgment_number = self.tcb.get_ack();
+        let mut options = Vec::new();
         if flags & tcp_flags::SYN != 0 {
             tcp_header.syn = true;
+            if let Some((_, recv)) = self.tcb.get_window_scale() {
+                options.extend([TcpOptionElement::Noop, TcpOptionElement::WindowScale(recv)]);
+            }
+        }
+        if let Some((ts_val, ts_ecr)) = self.tcb.get_timestamps() {
+            options.extend([
+                TcpOptionElement::Noop,
+                TcpOptionElement::Noop,
+                TcpOptionElement::Timestamp(ts_val, ts_ecr),
+            ]);
+        }
+        if !options.is_empty() {
+            tcp_header
+                .set_options(&options)
+                .map_err(|_e| Error::from(ErrorKind::InvalidInput))?;
         }
         if flags & tcp_flags::ACK != 0 {
             tcp_header.ack = true;
@@ -118,6 +306,9 @@ impl IpStackTcpStream {
         let ip_header = match (self.dst_addr.ip(), self.src_addr.ip()) {
             (std::net::IpAddr::V4(dst), std::net::IpAddr::V4(src)) => {
                 let mut ip_h = Ipv4Header::new(0, ttl, 6, dst.octets(), src.octets());
+                ip_h.identification = self.ip_id.next();
+                ip_h.differentiated_services_code_point = self.traffic_class >> 2;
+                ip_h.explicit_congestion_notification = self.traffic_class & 0b11;
                 let payload_len =
                     self.calculate_payload_len(ip_h.header_len() as u16, tcp_header.header_len());
                 payload.truncate(payload_len as usize);
@@ -127,7 +318,7 @@ impl IpStackTcpStream {
             }
             (std::net::IpAddr::V6(dst), std::net::IpAddr::V6(src)) => {
                 let mut ip_h = etherparse::Ipv6Header {
-                    traffic_class: 0,
+                    traffic_class: self.traffic_class,
                     flow_label: 0,
                     payload_length: 0,
                     next_header: 6,
@@ -145,18 +336,7 @@ impl IpStackTcpStream {
             _ => unreachable!(),
         };
 
-        match ip_header {
-            etherparse::IpHeader::Version4(ref ip_header, _) => {
-                tcp_header.checksum = tcp_header
-                    .calc_checksum_ipv4(ip_header, &payload)
-                    .map_err(|_e| Error::from(ErrorKind::InvalidInput))?;
-            }
-            etherparse::IpHeader::Version6(ref ip_header, _) => {
-                tcp_header.checksum = tcp_header
-                    .calc_checksum_ipv6(ip_header, &payload)
-                    .map_err(|_e| Error::from(ErrorKind::InvalidInput))?;
-            }
-        }
+        tcp_header.checksum = tcp_checksum(&ip_header, &tcp_header, &payload)?;
         Ok(NetworkPacket {
             ip: ip_header,
             transport: TransportHeader::Tcp(tcp_header),
@@ -169,6 +349,348 @@ impl IpStackTcpStream {
     pub fn peer_addr(&self) -> SocketAddr {
         self.dst_addr
     }
+    pub fn network_tuple(&self) -> NetworkTuple {
+        NetworkTuple::new(self.src_addr, self.dst_addr, true)
+    }
+    /// Resolves once the handshake has completed, driving the flow without
+    /// consuming any received data.
+    pub async fn established(&mut self) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| loop {
+            match self.tcb.get_state() {
+                TcpState::SynReceived(_) => {}
+                TcpState::Closed => {
+                    return std::task::Poll::Ready(Err(Error::from(ErrorKind::NotConnected)))
+                }
+                _ => return std::task::Poll::Ready(Ok(())),
+            }
+            let mut empty = tokio::io::ReadBuf::new(&mut []);
+            if Pin::new(&mut *self).poll_read(cx, &mut empty)?.is_pending() {
+                return std::task::Poll::Pending;
+            }
+        })
+        .await
+    }
+    /// The window most recently advertised by the peer, with its window
+    /// scale applied.
+    pub fn peer_window(&self) -> u32 {
+        self.tcb.get_send_window()
+    }
+    /// Largest payload a segment sent on this flow carries: the MTU less the
+    /// IP and TCP headers, capped by the peer's MSS (536, or 1220 over IPv6,
+    /// if its SYN had none), less the options every segment carries.
+    pub fn effective_mss(&self) -> u16 {
+        let ip_header_len = match self.dst_addr {
+            SocketAddr::V4(_) => IPV4_HEADER_LEN,
+            SocketAddr::V6(_) => IPV6_HEADER_LEN,
+        };
+        let options_len = match self.tcb.get_timestamps() {
+            Some(_) => TIMESTAMPS_OPTION_LEN,
+            None => 0,
+        };
+        self.max_payload_len(ip_header_len, TCP_HEADER_LEN + options_len)
+    }
+    /// Whether the peer has finished sending, so reads only drain what had
+    /// already arrived.
+    pub fn read_closed(&self) -> bool {
+        matches!(
+            self.tcb.get_state(),
+            TcpState::CloseWait | TcpState::LastAck | TcpState::Closed
+        )
+    }
+    /// Whether our side has been shut down, so writes fail.
+    pub fn write_closed(&self) -> bool {
+        matches!(
+            self.tcb.get_state(),
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::LastAck | TcpState::Closed
+        )
+    }
+    // After the peer's FIN the application may stop reading, so whatever is
+    // written or shut down drives the flow itself to see its ACKs.
+    fn poll_half_closed(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        if self.read_closed() && self.tcb.get_state() != &TcpState::Closed {
+            let mut empty = tokio::io::ReadBuf::new(&mut []);
+            if let std::task::Poll::Ready(Err(e)) = Pin::new(&mut *self).poll_read(cx, &mut empty) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// Bytes received from the peer and not yet read, including segments
+    /// held out of order.
+    pub fn recv_buffer_len(&self) -> usize {
+        self.read_stash.len() + self.tcb.get_read_buffer_len()
+    }
+    /// Bytes the reassembly buffer holds at most, which bounds the window
+    /// advertised to the peer.
+    pub fn recv_buffer_capacity(&self) -> usize {
+        READ_BUFFER_SIZE
+    }
+    /// Overrides the TTL (or hop limit) of the packets this flow sends from
+    /// now on. It must be at least 1.
+    pub fn set_ttl(&mut self, ttl: u8) -> std::io::Result<()> {
+        if ttl == 0 {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        self.ttl = ttl;
+        Ok(())
+    }
+    /// Sets the traffic class (for IPv4, the DSCP and ECN bits) of the packets
+    /// this flow sends from now on. It starts as the DSCP of the peer's SYN,
+    /// with the ECN bits Not-ECT.
+    pub fn set_traffic_class(&mut self, traffic_class: u8) {
+        self.traffic_class = traffic_class;
+    }
+    /// Most verbose level this flow's own events are logged at, `INFO`
+    /// unless `IpStackConfig::flow_log_level` chose another. Its segments and
+    /// retransmissions are logged at `DEBUG`.
+    pub fn set_log_level(&mut self, level: Level) {
+        self.log_level = level;
+    }
+    /// Snapshot of the flow's control block.
+    pub fn debug_tcb(&self) -> TcbSnapshot {
+        self.tcb.snapshot()
+    }
+    /// Number of segments retransmitted on this flow so far.
+    pub fn retransmit_count(&self) -> u32 {
+        self.tcb.get_retransmit_count()
+    }
+    /// Payload bytes read from and written to this flow so far.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.transferred
+    }
+    /// Time from the peer's SYN arriving to the ACK completing the handshake,
+    /// once it has. The ACK is timed as the stream handles it, so a stream
+    /// first polled late reports that delay too.
+    pub fn handshake_rtt(&self) -> Option<Duration> {
+        self.handshake_rtt
+    }
+    /// Segments dropped as tinygrams under [`TinygramPolicy::Drop`].
+    pub fn tinygrams_dropped(&self) -> u64 {
+        self.tinygrams_dropped
+    }
+    /// Zero-window probes sent since the peer's window last closed.
+    pub fn probe_count(&self) -> u32 {
+        self.probe_count
+    }
+    /// Returns taps of the bytes received from the peer and the bytes written
+    /// to it, in that order. A new call replaces the previous taps.
+    pub fn tap(&mut self) -> (DuplexTap, DuplexTap) {
+        let (ingress_sender, ingress) = DuplexTap::new();
+        let (egress_sender, egress) = DuplexTap::new();
+        self.ingress_tap = Some(ingress_sender);
+        self.egress_tap = Some(egress_sender);
+        (ingress, egress)
+    }
+    /// Closes the flow at `deadline` regardless of activity, as configured by
+    /// [`IpStackConfig::deadline_policy`].
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+    }
+    /// Makes writes still unable to make progress at `deadline`, for want of
+    /// window or buffer space, fail with [`ErrorKind::TimedOut`]. Writes that
+    /// can proceed are unaffected.
+    pub fn set_write_deadline(&mut self, deadline: Instant) {
+        self.write_deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+    }
+    // Resends the earliest unacknowledged segment once the retransmission
+    // timer expires, which is our FIN once all data before it is acknowledged.
+    fn poll_rto(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        if !self.tcb.poll_rto(cx) {
+            return Ok(());
+        }
+        self.tcb.retransmission = self.tcb.inflight_packets.first().map(|p| p.seq);
+        if let (None, Some(seq)) = (self.tcb.retransmission, self.tcb.get_sent_fin()) {
+            let packet = self.create_rev_packet(
+                tcp_flags::FIN | tcp_flags::ACK,
+                self.ttl,
+                Some(seq),
+                Vec::new(),
+            )?;
+            self.packet_sender
+                .send(packet)
+                .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+            self.tcb.add_retransmit();
+            flow_event!(self, Level::DEBUG, "retransmitted FIN seq {}", seq);
+            return Ok(());
+        }
+        if let std::task::Poll::Ready(Err(e)) = Pin::new(&mut *self).poll_flush(cx) {
+            return Err(e);
+        }
+        Ok(())
+    }
+    fn poll_write_deadline(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let expired = self
+            .write_deadline
+            .as_mut()
+            .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+        if expired {
+            return Err(Error::from(ErrorKind::TimedOut));
+        }
+        Ok(())
+    }
+    // The stack has already reset the peer and forgotten the flow.
+    fn check_reaped(&mut self) -> Result<(), Error> {
+        if !self.shared.reaped.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.removed = true;
+        self.tcb.change_state(TcpState::Closed);
+        Err(Error::from(ErrorKind::ConnectionReset))
+    }
+    // Both FINs are acknowledged. Sent right away, as nothing may poll the
+    // flow once it has closed.
+    fn close_flow(&mut self) -> Result<(), Error> {
+        if let Some(packet) = self.packet_to_send.take() {
+            self.packet_sender
+                .send(packet)
+                .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+        }
+        let packet = self.create_drop_packet(CloseReason::LocalFin)?;
+        self.packet_sender
+            .send(packet)
+            .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+        self.tcb.change_state(TcpState::Closed);
+        Ok(())
+    }
+    // Sends our FIN unless it's already out, right away so it doesn't wait on
+    // the flow being read.
+    fn send_fin(&mut self) -> Result<(), Error> {
+        let next = match self.tcb.get_state() {
+            TcpState::Established => TcpState::FinWait1,
+            TcpState::CloseWait => TcpState::LastAck,
+            _ => return Ok(()),
+        };
+        if let Some(packet) = self.packet_to_send.take() {
+            self.packet_sender
+                .send(packet)
+                .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+        }
+        self.packet_sender
+            .send(self.create_rev_packet(
+                tcp_flags::FIN | tcp_flags::ACK,
+                self.ttl,
+                None,
+                Vec::new(),
+            )?)
+            .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+        self.tcb.add_sent_fin();
+        self.tcb.change_state(next);
+        Ok(())
+    }
+    // Hands out as much of `read_stash` as `buf` has room for.
+    fn take_stash(&mut self, buf: &mut tokio::io::ReadBuf<'_>) {
+        let len = cmp::min(buf.remaining(), self.read_stash.len());
+        buf.put_slice(&self.read_stash[..len]);
+        self.read_stash.drain(..len);
+    }
+    // While the peer's window is closed, probes it so a lost window update
+    // can't stall the writer. The probe is an ACK one byte below `seq`, which
+    // the peer answers with its current window.
+    fn poll_persist(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        if self.tcb.send_window != 0 {
+            self.persist = None;
+            self.probe_count = 0;
+            return Ok(());
+        }
+        let (interval, jitter) = (self.persist_interval, self.timer_jitter);
+        loop {
+            let (timer, _) = self.persist.get_or_insert_with(|| {
+                let timer = tokio::time::sleep(jittered(interval, jitter));
+                (Box::pin(timer), interval)
+            });
+            if timer.as_mut().poll(cx).is_pending() {
+                return Ok(());
+            }
+            let probe = self.create_rev_packet(
+                tcp_flags::ACK,
+                self.ttl,
+                Some(self.tcb.get_seq().wrapping_sub(1)),
+                Vec::new(),
+            )?;
+            self.packet_sender
+                .send(probe)
+                .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+            self.probe_count += 1;
+            if let Some((timer, interval)) = self.persist.as_mut() {
+                *interval = cmp::min(*interval * 2, self.persist_max_interval);
+                timer
+                    .as_mut()
+                    .reset(Instant::now() + jittered(*interval, self.timer_jitter));
+            }
+        }
+    }
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let Some(deadline) = self.deadline.as_mut() else {
+            return Ok(());
+        };
+        if deadline.as_mut().poll(cx).is_pending() {
+            return Ok(());
+        }
+        match self.deadline_policy {
+            ClosePolicy::Graceful => {
+                self.deadline = None;
+                self.close_with(ClosePolicy::Graceful, CloseReason::Deadline)
+            }
+            // The deadline is kept so every later call fails the same way.
+            ClosePolicy::Reset => {
+                self.close_with(ClosePolicy::Reset, CloseReason::Deadline)?;
+                Err(Error::from(ErrorKind::TimedOut))
+            }
+        }
+    }
+    // Once the quota is spent, the flow is closed as `quota_policy` says.
+    // The quota stays armed, so a flow still in the handshake is closed once
+    // it's established, and data the peer sends after our FIN is refused.
+    fn check_quota(&mut self) -> Result<(), Error> {
+        if !self.quota_spent() {
+            return Ok(());
+        }
+        match self.quota_policy {
+            ClosePolicy::Graceful => {
+                self.close_with(ClosePolicy::Graceful, CloseReason::QuotaExceeded)
+            }
+            // Likewise the quota is kept, see `poll_deadline`.
+            ClosePolicy::Reset => {
+                self.close_with(ClosePolicy::Reset, CloseReason::QuotaExceeded)?;
+                Err(Error::from(ErrorKind::ConnectionAborted))
+            }
+        }
+    }
+    fn quota_spent(&self) -> bool {
+        self.quota.is_some_and(|quota| self.transferred >= quota)
+    }
+    // Closes the flow, unless it's already closing, as `policy` says.
+    fn close_with(&mut self, policy: ClosePolicy, reason: CloseReason) -> Result<(), Error> {
+        match policy {
+            ClosePolicy::Graceful => {
+                if matches!(
+                    self.tcb.get_state(),
+                    TcpState::Established | TcpState::CloseWait
+                ) {
+                    self.close_reason.get_or_insert(reason);
+                    self.send_fin()?;
+                }
+            }
+            ClosePolicy::Reset => {
+                if self.tcb.get_state() != &TcpState::Closed {
+                    self.packet_sender
+                        .send(self.create_rev_packet(
+                            tcp_flags::RST | tcp_flags::ACK,
+                            self.ttl,
+                            None,
+                            Vec::new(),
+                        )?)
+                        .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+                    let packet = self.create_drop_packet(reason)?;
+                    self.packet_sender
+                        .send(packet)
+                        .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+                    self.tcb.change_state(TcpState::Closed);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl AsyncRead for IpStackTcpStream {
@@ -177,15 +699,21 @@ impl AsyncRead for IpStackTcpStream {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
+        self.shared.polled.store(true, Ordering::Relaxed);
+        self.check_reaped()?;
+        if !self.read_stash.is_empty() {
+            self.take_stash(buf);
+            return std::task::Poll::Ready(Ok(()));
+        }
         loop {
-            if matches!(self.tcb.get_state(), TcpState::FinWait2(false)) {
-                self.packet_to_send =
-                    Some(self.create_rev_packet(0, DROP_TTL, None, Vec::new())?);
-                self.tcb.change_state(TcpState::Closed);
-                return std::task::Poll::Ready(Ok(()));
-            }
-            let min = cmp::min(self.tcb.get_available_read_buffer_size() as u16, u16::MAX);
-            self.tcb.change_recv_window(min);
+            self.poll_deadline(cx)?;
+            self.check_quota()?;
+            self.poll_rto(cx)?;
+            let window = match self.initial_recv_window {
+                Some(window) if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) => window,
+                _ => cmp::min(self.tcb.get_available_read_buffer_size() as u16, u16::MAX),
+            };
+            self.tcb.change_recv_window(window);
             // Timeout only applies to handshake.
             // Otherwise it kills long-running connections.
             if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) && matches!(
@@ -196,18 +724,23 @@ impl AsyncRead for IpStackTcpStream {
                 self.packet_sender
                     .send(self.create_rev_packet(
                         tcp_flags::RST | tcp_flags::ACK,
-                        TTL,
+                        self.ttl,
                         None,
                         Vec::new(),
                     )?)
                     .map_err(|_| ErrorKind::UnexpectedEof)?;
+                let packet = self.create_drop_packet(CloseReason::IdleTimeout)?;
+                self.packet_sender
+                    .send(packet)
+                    .map_err(|_| ErrorKind::UnexpectedEof)?;
+                self.tcb.change_state(TcpState::Closed);
                 return std::task::Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
             }
 
             if matches!(self.tcb.get_state(), TcpState::SynReceived(false)) {
                 self.packet_to_send = Some(self.create_rev_packet(
                     tcp_flags::SYN | tcp_flags::ACK,
-                    TTL,
+                    self.ttl,
                     None,
                     Vec::new(),
                 )?);
@@ -220,28 +753,61 @@ impl AsyncRead for IpStackTcpStream {
                     .send(packet)
                     .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
                 if matches!(self.tcb.get_state(), TcpState::Closed) {
-                    if let Some(shutdown) = self.shutdown.take() {
-                        shutdown.notify_one();
-                    }
                     return std::task::Poll::Ready(Ok(()));
                 }
             }
             if let Some(b) = self.tcb.get_unordered_packets() {
                 self.tcb.add_ack(b.len() as u32);
-                buf.put_slice(&b);
+                self.transferred += b.len() as u64;
+                // Tinygrams are held back until nothing more is queued, and
+                // anything held goes out ahead of what follows it.
+                let hold = self.tinygram_policy == TinygramPolicy::Coalesce
+                    && self.read_stash.len() + b.len() < self.min_segment_size;
+                if hold || !self.read_stash.is_empty() {
+                    self.read_stash.extend_from_slice(&b);
+                    if !hold {
+                        self.take_stash(buf);
+                    }
+                } else {
+                    // Whatever doesn't fit is handed out by the next read.
+                    let len = cmp::min(buf.remaining(), b.len());
+                    buf.put_slice(&b[..len]);
+                    self.read_stash.extend_from_slice(&b[len..]);
+                }
+                if let Some(ref tap) = self.ingress_tap {
+                    _ = tap.try_send(b);
+                }
+                if hold {
+                    continue;
+                }
                 self.packet_sender
-                    .send(self.create_rev_packet(tcp_flags::ACK, TTL, None, Vec::new())?)
+                    .send(self.create_rev_packet(tcp_flags::ACK, self.ttl, None, Vec::new())?)
                     .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
                 return std::task::Poll::Ready(Ok(()));
             }
-            if self.shutdown.is_some() && matches!(self.tcb.get_state(), TcpState::Established) {
-                self.tcb.change_state(TcpState::FinWait1);
-                self.packet_to_send = Some(self.create_rev_packet(
-                    tcp_flags::FIN | tcp_flags::ACK,
-                    TTL,
-                    None,
-                    Vec::new(),
-                )?);
+            // The peer's FIN only takes effect once everything before it has
+            // been read.
+            let state = *self.tcb.get_state();
+            if matches!(
+                state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            ) && self.tcb.take_fin()
+            {
+                self.packet_to_send =
+                    Some(self.create_rev_packet(tcp_flags::ACK, self.ttl, None, Vec::new())?);
+                match state {
+                    TcpState::Established => {
+                        self.close_reason.get_or_insert(CloseReason::PeerFin);
+                        self.tcb.change_state(TcpState::CloseWait);
+                    }
+                    // Both FINs crossed: only the ACK of ours is left to wait for.
+                    TcpState::FinWait1 => self.tcb.change_state(TcpState::LastAck),
+                    // There's no TIME-WAIT, the flow is dropped once our ACK is out.
+                    _ => {
+                        self.close_flow()?;
+                        return std::task::Poll::Ready(Ok(()));
+                    }
+                }
                 continue;
             }
             match self.stream_receiver.poll_recv(cx) {
@@ -249,29 +815,71 @@ impl AsyncRead for IpStackTcpStream {
                     let IpStackPacketProtocol::Tcp(t) = p.transport_protocol() else {
                         unreachable!()
                     };
+                    flow_event!(
+                        self,
+                        Level::DEBUG,
+                        "segment seq {} ack {} flags {:#04x} len {} in {:?}",
+                        t.inner().sequence_number,
+                        t.inner().acknowledgment_number,
+                        t.flags(),
+                        p.payload.len(),
+                        self.tcb.get_state()
+                    );
                     if t.flags() & tcp_flags::RST != 0 {
-                        self.packet_to_send =
-                            Some(self.create_rev_packet(0, DROP_TTL, None, Vec::new())?);
+                        let packet = self.create_drop_packet(CloseReason::PeerReset)?;
+                        self.packet_sender
+                            .send(packet)
+                            .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
                         self.tcb.change_state(TcpState::Closed);
                         return std::task::Poll::Ready(Err(Error::from(
                             ErrorKind::ConnectionReset,
                         )));
                     }
+                    // RFC 5961 section 5.2: an ACK beyond anything sent is
+                    // answered with a challenge ACK and otherwise ignored.
+                    if t.flags() & tcp_flags::ACK != 0
+                        && self.tcb.is_future_ack(t.inner().acknowledgment_number)
+                    {
+                        self.packet_to_send = Some(self.create_rev_packet(
+                            tcp_flags::ACK,
+                            self.ttl,
+                            None,
+                            Vec::new(),
+                        )?);
+                        continue;
+                    }
                     if matches!(
                         self.tcb.check_pkt_type(&t, &p.payload),
                         PacketStatus::Invalid
                     ) {
                         continue;
                     }
+                    if let Some((ts_val, _)) = t.options().timestamps {
+                        self.tcb.update_ts_recent(ts_val);
+                    }
 
-                    if matches!(self.tcb.get_state(), TcpState::SynReceived(true)) {
-                        if t.flags() == tcp_flags::ACK {
-                            self.tcb.change_last_ack(t.inner().acknowledgment_number);
-                            self.tcb.change_send_window(t.inner().window_size);
-                            self.tcb.change_state(TcpState::Established);
+                    // Any segment acking our SYN-ACK completes the handshake,
+                    // in case the bare ACK before it was lost or reordered.
+                    // Whatever else it carries is then handled as established.
+                    if matches!(self.tcb.get_state(), TcpState::SynReceived(true))
+                        && t.flags() & (tcp_flags::SYN | tcp_flags::ACK) == tcp_flags::ACK
+                    {
+                        self.tcb.change_last_ack(t.inner().acknowledgment_number);
+                        self.tcb.change_send_window(t.inner().window_size);
+                        self.tcb.change_state(TcpState::Established);
+                        self.handshake_rtt = Some(self.syn_received.elapsed());
+                        if let Some(n) = self.write_notify.take() {
+                            n.wake();
                         }
-                    } else if matches!(self.tcb.get_state(), TcpState::Established) {
-                        if t.flags() == tcp_flags::ACK {
+                    }
+                    if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) {
+                        continue;
+                    } else if matches!(
+                        self.tcb.get_state(),
+                        TcpState::Established | TcpState::CloseWait
+                    ) {
+                        // PSH only asks for prompt delivery, which every segment gets.
+                        if t.flags() & !tcp_flags::PSH == tcp_flags::ACK {
                             match self.tcb.check_pkt_type(&t, &p.payload) {
                                 PacketStatus::WindowUpdate => {
                                     self.tcb.change_send_window(t.inner().window_size);
@@ -287,7 +895,7 @@ impl AsyncRead for IpStackTcpStream {
                                     self.tcb.change_send_window(t.inner().window_size);
                                     self.packet_to_send = Some(self.create_rev_packet(
                                         tcp_flags::ACK,
-                                        TTL,
+                                        self.ttl,
                                         None,
                                         Vec::new(),
                                     )?);
@@ -295,6 +903,9 @@ impl AsyncRead for IpStackTcpStream {
                                 }
                                 PacketStatus::RetransmissionRequest => {
                                     self.tcb.change_send_window(t.inner().window_size);
+                                    if !self.tcb.add_dup_ack() {
+                                        continue;
+                                    }
                                     self.tcb.retransmission = Some(t.inner().acknowledgment_number);
                                     if matches!(
                                         self.as_mut().poll_flush(cx),
@@ -309,7 +920,7 @@ impl AsyncRead for IpStackTcpStream {
                                     //     dbg!(t.inner().sequence_number);
                                     //     self.packet_to_send = Some(self.create_rev_packet(
                                     //         tcp_flags::ACK,
-                                    //         TTL,
+                                    //         self.ttl,
                                     //         None,
                                     //         Vec::new(),
                                     //     )?);
@@ -317,15 +928,23 @@ impl AsyncRead for IpStackTcpStream {
                                     // }
 
                                     self.tcb.change_last_ack(t.inner().acknowledgment_number);
-                                    self.tcb.add_unordered_packet(
-                                        t.inner().sequence_number,
-                                        &p.payload,
-                                    );
+                                    if self.tinygram_policy == TinygramPolicy::Drop
+                                        && !p.payload.is_empty()
+                                        && p.payload.len() < self.min_segment_size
+                                        && !self.stream_receiver.is_empty()
+                                    {
+                                        self.tinygrams_dropped += 1;
+                                    } else {
+                                        self.tcb.add_unordered_packet(
+                                            t.inner().sequence_number,
+                                            &p.payload,
+                                        );
+                                    }
                                     // buf.put_slice(&p.payload);
                                     // self.tcb.add_ack(p.payload.len() as u32);
                                     // self.packet_to_send = Some(self.create_rev_packet(
                                     //     tcp_flags::ACK,
-                                    //     TTL,
+                                    //     self.ttl,
                                     //     None,
                                     //     Vec::new(),
                                     // )?);
@@ -348,67 +967,101 @@ impl AsyncRead for IpStackTcpStream {
                                 }
                             };
                         }
-                        if t.flags() == (tcp_flags::FIN | tcp_flags::ACK) {
-                            self.tcb.add_ack(1);
-                            self.packet_to_send = Some(self.create_rev_packet(
-                                tcp_flags::FIN | tcp_flags::ACK,
-                                TTL,
-                                None,
-                                Vec::new(),
-                            )?);
-                            self.tcb.add_seq_one();
-                            self.tcb.change_state(TcpState::FinWait2(true));
-                            continue;
-                        }
-                        if t.flags() == (tcp_flags::PSH | tcp_flags::ACK) {
-                            if !matches!(
-                                self.tcb.check_pkt_type(&t, &p.payload),
-                                PacketStatus::NewPacket
-                            ) {
-                                continue;
-                            }
+                        // The peer is done sending; our side stays open until
+                        // it is shut down. Data it carries is queued like any
+                        // other, ahead of the FIN.
+                        if t.flags() & !tcp_flags::PSH == (tcp_flags::FIN | tcp_flags::ACK)
+                            && matches!(self.tcb.get_state(), TcpState::Established)
+                        {
                             self.tcb.change_last_ack(t.inner().acknowledgment_number);
-
-                            if p.payload.is_empty()
-                                || self.tcb.get_ack() != t.inner().sequence_number
-                            {
-                                continue;
-                            }
-
-                            // self.tcb.add_ack(p.payload.len() as u32);
                             self.tcb.change_send_window(t.inner().window_size);
-                            // buf.put_slice(&p.payload);
-                            // self.packet_to_send = Some(self.create_rev_packet(
-                            //     tcp_flags::ACK,
-                            //     TTL,
-                            //     None,
-                            //     Vec::new(),
-                            // )?);
-                            // return std::task::Poll::Ready(Ok(()));
-                            self.tcb
-                                .add_unordered_packet(t.inner().sequence_number, &p.payload);
+                            self.tcb.add_fin(t.inner().sequence_number, &p.payload);
                             continue;
                         }
-                    } else if matches!(self.tcb.get_state(), TcpState::FinWait1) {
-                        if t.flags() == (tcp_flags::FIN | tcp_flags::ACK) {
+                        // A retransmitted FIN means our ACK of it was lost.
+                        if t.flags() & tcp_flags::FIN != 0
+                            && matches!(self.tcb.get_state(), TcpState::CloseWait)
+                        {
                             self.packet_to_send = Some(self.create_rev_packet(
                                 tcp_flags::ACK,
-                                TTL,
+                                self.ttl,
                                 None,
                                 Vec::new(),
                             )?);
-                            self.tcb.change_send_window(t.inner().window_size);
-                            self.tcb.add_seq_one();
-                            self.tcb.change_state(TcpState::FinWait2(false));
                             continue;
                         }
-                    } else if matches!(self.tcb.get_state(), TcpState::FinWait2(true)) {
-                        if t.flags() == tcp_flags::ACK {
-                            self.tcb.change_state(TcpState::FinWait2(false));
+                    } else if matches!(
+                        self.tcb.get_state(),
+                        TcpState::FinWait1 | TcpState::FinWait2 | TcpState::LastAck
+                    ) {
+                        // Our FIN holds the last sequence number, so an ACK of
+                        // `seq` acknowledges it, with or without data.
+                        if t.flags() & tcp_flags::ACK != 0 {
+                            self.tcb.change_last_ack(t.inner().acknowledgment_number);
+                            self.tcb.change_send_window(t.inner().window_size);
+                            if t.inner().acknowledgment_number == self.tcb.get_seq() {
+                                match self.tcb.get_state() {
+                                    TcpState::FinWait1 => self.tcb.change_state(TcpState::FinWait2),
+                                    TcpState::LastAck => {
+                                        self.close_flow()?;
+                                        return std::task::Poll::Ready(Ok(()));
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        }
+                        // New data past a spent quota can't be read, so the
+                        // peer still sending it after our FIN is reset.
+                        let end = t
+                            .inner()
+                            .sequence_number
+                            .wrapping_add(p.payload.len() as u32);
+                        if self.quota_spent()
+                            && (end.wrapping_sub(self.tcb.get_ack()) as i32) > 0
+                            && !matches!(self.tcb.get_state(), TcpState::LastAck)
+                        {
+                            self.close_with(ClosePolicy::Reset, CloseReason::QuotaExceeded)?;
+                            return std::task::Poll::Ready(Err(Error::from(
+                                ErrorKind::ConnectionAborted,
+                            )));
+                        }
+                        if t.flags() & tcp_flags::FIN != 0 {
+                            if matches!(self.tcb.get_state(), TcpState::LastAck) {
+                                // A retransmitted FIN means our ACK of it was lost.
+                                self.packet_to_send = Some(self.create_rev_packet(
+                                    tcp_flags::ACK,
+                                    self.ttl,
+                                    None,
+                                    Vec::new(),
+                                )?);
+                            } else {
+                                self.tcb.add_fin(t.inner().sequence_number, &p.payload);
+                            }
+                        } else if !p.payload.is_empty()
+                            && !matches!(self.tcb.get_state(), TcpState::LastAck)
+                        {
+                            // Our FIN only closed our side: the peer's data is
+                            // read and acked as usual until its own FIN.
+                            self.tcb
+                                .add_unordered_packet(t.inner().sequence_number, &p.payload);
                         }
                     }
                 }
+                // Tinygrams held back are handed out once nothing more is queued.
+                std::task::Poll::Ready(None) | std::task::Poll::Pending
+                    if !self.read_stash.is_empty() =>
+                {
+                    self.take_stash(buf);
+                    self.packet_sender
+                        .send(self.create_rev_packet(tcp_flags::ACK, self.ttl, None, Vec::new())?)
+                        .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+                    return std::task::Poll::Ready(Ok(()));
+                }
                 std::task::Poll::Ready(None) => return std::task::Poll::Ready(Ok(())),
+                // Nothing more will arrive to be read.
+                std::task::Poll::Pending if matches!(self.tcb.get_state(), TcpState::CloseWait) => {
+                    return std::task::Poll::Ready(Ok(()))
+                }
                 std::task::Poll::Pending => return std::task::Poll::Pending,
             }
         }
@@ -421,9 +1074,37 @@ impl AsyncWrite for IpStackTcpStream {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        if (self.tcb.send_window as u64) < self.tcb.avg_send_window.0 / 2
+        self.check_reaped()?;
+        self.poll_deadline(cx)?;
+        self.check_quota()?;
+        self.poll_rto(cx)?;
+        self.poll_half_closed(cx)?;
+        // Data may only follow the handshake, so a write drives it and waits
+        // for it to complete.
+        if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) {
+            let mut empty = tokio::io::ReadBuf::new(&mut []);
+            if let std::task::Poll::Ready(Err(e)) = self.as_mut().poll_read(cx, &mut empty) {
+                return std::task::Poll::Ready(Err(e));
+            }
+            if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) {
+                self.poll_write_deadline(cx)?;
+                self.write_notify = Some(cx.waker().clone());
+                return std::task::Poll::Pending;
+            }
+        }
+        if matches!(
+            self.tcb.get_state(),
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::LastAck | TcpState::Closed
+        ) {
+            return std::task::Poll::Ready(Err(Error::from(ErrorKind::BrokenPipe)));
+        }
+        self.poll_persist(cx)?;
+        // Parked until an ACK or window update opens room, see `poll_read`.
+        if self.tcb.get_usable_window() == 0
+            || (self.tcb.send_window as u64) < self.tcb.avg_send_window.0 / 2
             || self.tcb.is_send_buffer_full()
         {
+            self.poll_write_deadline(cx)?;
             self.write_notify = Some(cx.waker().clone());
             return std::task::Poll::Pending;
         }
@@ -431,24 +1112,68 @@ impl AsyncWrite for IpStackTcpStream {
         if self.tcb.retransmission.is_some() {
             self.write_notify = Some(cx.waker().clone());
             if matches!(self.as_mut().poll_flush(cx), std::task::Poll::Pending) {
+                self.poll_write_deadline(cx)?;
                 return std::task::Poll::Pending;
             }
         }
 
-        let packet =
-            self.create_rev_packet(tcp_flags::PSH | tcp_flags::ACK, TTL, None, buf.to_vec())?;
-        let seq = self.tcb.seq;
-        let payload_len = packet.payload.len();
-        let payload = packet.payload.clone();
+        let buf = match self.quota {
+            Some(quota) => {
+                let left =
+                    usize::try_from(quota.saturating_sub(self.transferred)).unwrap_or(usize::MAX);
+                &buf[..cmp::min(buf.len(), left)]
+            }
+            None => buf,
+        };
+        // Segments are enqueued up to `max_write_segments` per call, so one
+        // large write can't monopolize the egress loop.
+        let mut written = 0;
+        for i in 0..self.max_write_segments {
+            let usable_window = self.tcb.get_usable_window() as usize;
+            if written == buf.len()
+                || usable_window == 0
+                || (written != 0 && self.tcb.is_send_buffer_full())
+            {
+                break;
+            }
+            let end = cmp::min(buf.len(), written + usable_window);
+            let mut packet =
+                self.create_rev_packet(tcp_flags::ACK, self.ttl, None, buf[written..end].to_vec())?;
+            let payload_len = packet.payload.len();
+            if payload_len == 0 {
+                break;
+            }
+            // The segment ending the write carries PSH, so the peer delivers
+            // what has been written without waiting for more. It's known only
+            // once the segment is cut to size, so the flag is set afterwards.
+            if i + 1 == self.max_write_segments
+                || written + payload_len == buf.len()
+                || payload_len == usable_window
+            {
+                if let TransportHeader::Tcp(ref mut tcp_header) = packet.transport {
+                    tcp_header.psh = true;
+                    tcp_header.checksum = tcp_checksum(&packet.ip, tcp_header, &packet.payload)?;
+                }
+            }
+            let seq = self.tcb.seq;
+            let payload = packet.payload.clone();
 
-        self.packet_sender
-            .send(packet)
-            .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
-        self.tcb.add_inflight_packet(seq, &payload);
+            self.packet_sender
+                .send(packet)
+                .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+            self.tcb.add_inflight_packet(seq, &payload);
+            written += payload.len();
+            if let Some(ref tap) = self.egress_tap {
+                _ = tap.try_send(payload);
+            }
+        }
 
-        std::task::Poll::Ready(Ok(payload_len))
+        self.transferred += written as u64;
+        std::task::Poll::Ready(Ok(written))
     }
 
+    // Writes are never held back and already end on a PSH segment, so only a
+    // pending retransmission is left for a flush to send.
     fn poll_flush(
         mut self: std::pin::Pin<&mut Self>,
         _cx: &mut std::task::Context<'_>,
@@ -461,7 +1186,7 @@ impl AsyncWrite for IpStackTcpStream {
         {
             let packet = self.create_rev_packet(
                 tcp_flags::PSH | tcp_flags::ACK,
-                TTL,
+                self.ttl,
                 Some(i.seq),
                 i.payload.to_vec(),
             )?;
@@ -469,17 +1194,19 @@ impl AsyncWrite for IpStackTcpStream {
             self.packet_sender
                 .send(packet)
                 .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
+            let seq = i.seq;
             self.tcb.retransmission = None;
-        } else if let Some(i) = self.tcb.retransmission {
-            warn!(i);
-            warn!(self.tcb.seq);
-            warn!(self.tcb.last_ack);
-            warn!(self.tcb.ack);
-            for p in self.tcb.inflight_packets.iter() {
-                warn!(p.seq);
-                warn!("{}", p.payload.len());
-            }
-            panic!("Please report these values at: https://github.com/narrowlink/ipstack/");
+            self.tcb.add_retransmit();
+            flow_event!(self, Level::DEBUG, "retransmitted seq {}", seq);
+        } else if let Some(seq) = self.tcb.retransmission.take() {
+            // The peer asked for data no segment sent starts at, which is
+            // left to it and the retransmission timer.
+            flow_event!(
+                self,
+                Level::DEBUG,
+                "no segment at seq {} to retransmit",
+                seq
+            );
         }
         std::task::Poll::Ready(Ok(()))
     }
@@ -488,28 +1215,44 @@ impl AsyncWrite for IpStackTcpStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        let notified = self.shutdown.get_or_insert(Notify::new()).notified();
-        match Pin::new(&mut Box::pin(notified)).poll(cx) {
-            std::task::Poll::Ready(_) => std::task::Poll::Ready(Ok(())),
-            std::task::Poll::Pending => std::task::Poll::Pending,
+        self.check_reaped()?;
+        self.poll_half_closed(cx)?;
+        // Like data, our FIN may only follow the handshake.
+        if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) {
+            let mut empty = tokio::io::ReadBuf::new(&mut []);
+            if let std::task::Poll::Ready(Err(e)) = self.as_mut().poll_read(cx, &mut empty) {
+                return std::task::Poll::Ready(Err(e));
+            }
+            if matches!(self.tcb.get_state(), TcpState::SynReceived(_)) {
+                self.write_notify = Some(cx.waker().clone());
+                return std::task::Poll::Pending;
+            }
         }
+        // Done once the FIN is out, the rest of the close is up to the flow
+        // being read or dropped.
+        self.send_fin()?;
+        std::task::Poll::Ready(Ok(()))
     }
 }
 
 impl Drop for IpStackTcpStream {
     fn drop(&mut self) {
         tracing::error!("Drop {}. {:?}", &self, self.tcb.get_state());
-        if self.tcb.get_state() != &TcpState::Closed {
-            self.packet_sender
-                    .send(self.create_rev_packet(
-                        tcp_flags::RST | tcp_flags::ACK,
-                        TTL,
-                        None,
-                        Vec::new(),
-                    ).unwrap()).unwrap();
+        if self.shared.reaped.load(Ordering::Relaxed) {
+            return;
         }
-        if let Ok(p) = self.create_rev_packet(0, DROP_TTL, None, Vec::new()) {
-            _ = self.packet_sender.send(p);
+        // Once our FIN is out, the flow is left to close without a RST.
+        if !self.write_closed() {
+            if let Ok(p) =
+                self.create_rev_packet(tcp_flags::RST | tcp_flags::ACK, self.ttl, None, Vec::new())
+            {
+                _ = self.packet_sender.send(p);
+            }
+        }
+        if !self.removed {
+            if let Ok(p) = self.create_drop_packet(CloseReason::Dropped) {
+                _ = self.packet_sender.send(p);
+            }
         }
     }
 }
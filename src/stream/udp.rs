@@ -4,6 +4,7 @@ use std::{
     io::{self, Error, ErrorKind},
     net::SocketAddr,
     pin::Pin,
+    sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
     time::Duration,
 };
@@ -15,10 +16,35 @@ use futures::{Sink, Stream};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    time::Sleep,
+    time::{Instant, Sleep},
 };
 
-use crate::{packet::NetworkPacket, TTL};
+use crate::{
+    packet::{NetworkPacket, NetworkTuple},
+    stream::{FlowShared, IpId},
+    CloseReason, IpStackConfig, DROP_TTL, TTL,
+};
+
+/// Direction of a UDP payload handed to a [`UdpPayloadTransform`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdpDirection {
+    /// Read from the device, e.g. a DNS query sent by the client.
+    Ingress,
+    /// Written to the device, e.g. a DNS response returned to the client.
+    Egress,
+}
+
+/// What happens to a datagram that would open a UDP flow past the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UdpOverflowPolicy {
+    /// Drop it silently.
+    #[default]
+    Drop,
+    /// Answer it with an ICMP (or ICMPv6) port unreachable.
+    PortUnreachable,
+}
+
+pub type UdpPayloadTransform = Arc<dyn Fn(UdpDirection, &[u8]) -> Vec<u8> + Send + Sync>;
 
 pub struct IpStackUdpStream {
     src_addr: SocketAddr,
@@ -30,12 +56,20 @@ pub struct IpStackUdpStream {
     timeout: Pin<Box<Sleep>>,
     udp_timeout: Duration,
     mtu: u16,
+    transform: Option<UdpPayloadTransform>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    removed: bool,
+    shared: Arc<FlowShared>,
+    ip_id: IpId,
+    ttl: u8,
+    traffic_class: u8,
 }
 
 impl Stream for IpStackUdpStream {
     type Item = NetworkPacket;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if matches!(self.timeout.as_mut().poll(cx), std::task::Poll::Ready(_)) {
+        self.shared.polled.store(true, Ordering::Relaxed);
+        if self.poll_deadline(cx) || self.poll_timeout(cx) {
             return Poll::Ready(None); // todo: return timeout error
         }
         let udp_timeout = self.udp_timeout;
@@ -44,7 +78,7 @@ impl Stream for IpStackUdpStream {
                 self.timeout
                     .as_mut()
                     .reset(tokio::time::Instant::now() + udp_timeout);
-                Poll::Ready(Some(p))
+                Poll::Ready(Some(self.transform_ingress(p)))
             }
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Pending => Poll::Pending,
@@ -52,6 +86,7 @@ impl Stream for IpStackUdpStream {
     }
 }
 
+#[allow(unused_variables)]
 impl Sink<&[u8]> for IpStackUdpStream {
     type Error = io::Error;
     fn start_send(mut self: Pin<&mut Self>, item: &[u8]) -> Result<(), Self::Error> {
@@ -59,7 +94,7 @@ impl Sink<&[u8]> for IpStackUdpStream {
         self.timeout
             .as_mut()
             .reset(tokio::time::Instant::now() + udp_timeout);
-        let packet = self.create_rev_packet(TTL, item.to_vec())?;
+        let packet = self.create_rev_packet(self.ttl, self.transform_egress(item))?;
         self.packet_sender
             .send(packet)
             .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
@@ -83,31 +118,75 @@ impl IpStackUdpStream {
         dst_addr: SocketAddr,
         payload: Vec<u8>,
         pkt_sender: UnboundedSender<NetworkPacket>,
-        mtu: u16,
-        udp_timeout: Duration,
+        config: &IpStackConfig,
     ) -> Self {
         let (stream_sender, stream_receiver) = mpsc::unbounded_channel::<NetworkPacket>();
+        let udp_timeout = config.udp_timeout;
+        let transform = config
+            .dns_transform
+            .clone()
+            .filter(|_| dst_addr.port() == config.dns_port);
+        let first_paload = match transform {
+            Some(ref transform) => transform(UdpDirection::Ingress, &payload),
+            None => payload,
+        };
         IpStackUdpStream {
             src_addr,
             dst_addr,
             stream_sender,
             stream_receiver,
             packet_sender: pkt_sender.clone(),
-            first_paload: Some(payload),
+            first_paload: Some(first_paload),
             timeout: Box::pin(tokio::time::sleep_until(
                 tokio::time::Instant::now() + udp_timeout,
             )),
             udp_timeout,
-            mtu,
+            mtu: config.mtu,
+            transform,
+            deadline: None,
+            removed: false,
+            shared: Arc::default(),
+            ip_id: IpId::new(config.randomize_flow_ids),
+            ttl: TTL,
+            traffic_class: 0,
         }
     }
     pub(crate) fn stream_sender(&self) -> UnboundedSender<NetworkPacket> {
         self.stream_sender.clone()
     }
+    pub(crate) fn shared(&self) -> Arc<FlowShared> {
+        self.shared.clone()
+    }
+    fn transform_ingress(&self, mut packet: NetworkPacket) -> NetworkPacket {
+        if let Some(ref transform) = self.transform {
+            packet.payload = transform(UdpDirection::Ingress, &packet.payload);
+        }
+        packet
+    }
+    fn transform_egress(&self, payload: &[u8]) -> Vec<u8> {
+        match self.transform {
+            Some(ref transform) => transform(UdpDirection::Egress, payload),
+            None => payload.to_vec(),
+        }
+    }
+    // Asks the stack to forget this flow, so later datagrams open a new one.
+    fn remove(&mut self, reason: CloseReason) {
+        if self.removed || self.shared.reaped.load(Ordering::Relaxed) {
+            return;
+        }
+        self.removed = true;
+        if let Ok(mut packet) = self.create_rev_packet(DROP_TTL, Vec::new()) {
+            packet.payload = vec![reason as u8];
+            _ = self.packet_sender.send(packet);
+        }
+    }
     fn create_rev_packet(&self, ttl: u8, mut payload: Vec<u8>) -> Result<NetworkPacket, Error> {
         match (self.dst_addr.ip(), self.src_addr.ip()) {
             (std::net::IpAddr::V4(dst), std::net::IpAddr::V4(src)) => {
                 let mut ip_h = Ipv4Header::new(0, ttl, 17, dst.octets(), src.octets());
+                ip_h.identification = self.ip_id.next();
+                ip_h.differentiated_services_code_point = self.traffic_class >> 2;
+                ip_h.explicit_congestion_notification = self.traffic_class & 0b11;
                 let line_buffer = self.mtu.saturating_sub(ip_h.header_len() as u16 + 8); // 8 is udp header size
                 payload.truncate(line_buffer as usize);
                 ip_h.payload_len = payload.len() as u16 + 8; // 8 is udp header size
@@ -126,7 +205,7 @@ impl IpStackUdpStream {
             }
             (std::net::IpAddr::V6(dst), std::net::IpAddr::V6(src)) => {
                 let mut ip_h = Ipv6Header {
-                    traffic_class: 0,
+                    traffic_class: self.traffic_class,
                     flow_label: 0,
                     payload_length: 0,
                     next_header: 17,
@@ -161,6 +240,51 @@ impl IpStackUdpStream {
     pub fn peer_addr(&self) -> SocketAddr {
         self.dst_addr
     }
+    pub fn network_tuple(&self) -> NetworkTuple {
+        NetworkTuple::new(self.src_addr, self.dst_addr, false)
+    }
+    /// Overrides the TTL (or hop limit) of the datagrams this flow sends from
+    /// now on. It must be at least 1.
+    pub fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        if ttl == 0 {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        self.ttl = ttl;
+        Ok(())
+    }
+    /// Sets the traffic class (for IPv4, the DSCP and ECN bits) of the
+    /// datagrams this flow sends from now on. It starts as the DSCP of the
+    /// datagram that opened the flow, with the ECN bits Not-ECT.
+    pub fn set_traffic_class(&mut self, traffic_class: u8) {
+        self.traffic_class = traffic_class;
+    }
+    /// Ends the flow at `deadline` regardless of activity: reads return EOF
+    /// and writes fail.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+    }
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> bool {
+        // The stack has already forgotten a reaped flow.
+        if self.shared.reaped.load(Ordering::Relaxed) {
+            self.removed = true;
+            return true;
+        }
+        let expired = self
+            .deadline
+            .as_mut()
+            .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+        if expired {
+            self.remove(CloseReason::Deadline);
+        }
+        expired
+    }
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> bool {
+        let expired = self.timeout.as_mut().poll(cx).is_ready();
+        if expired {
+            self.remove(CloseReason::IdleTimeout);
+        }
+        expired
+    }
 }
 
 impl AsyncRead for IpStackUdpStream {
@@ -169,17 +293,22 @@ impl AsyncRead for IpStackUdpStream {
         cx: &mut task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> task::Poll<io::Result<()>> {
+        self.shared.polled.store(true, Ordering::Relaxed);
+        if self.poll_deadline(cx) {
+            return Poll::Ready(Ok(()));
+        }
         if let Some(p) = self.first_paload.take() {
             buf.put_slice(&p);
             return Poll::Ready(Ok(()));
         }
-        if matches!(self.timeout.as_mut().poll(cx), std::task::Poll::Ready(_)) {
+        if self.poll_timeout(cx) {
             return Poll::Ready(Ok(())); // todo: return timeout error
         }
 
         let udp_timeout = self.udp_timeout;
         match self.stream_receiver.poll_recv(cx) {
             Poll::Ready(Some(p)) => {
+                let p = self.transform_ingress(p);
                 buf.put_slice(&p.payload);
                 self.timeout
                     .as_mut()
@@ -195,15 +324,22 @@ impl AsyncRead for IpStackUdpStream {
 impl AsyncWrite for IpStackUdpStream {
     fn poll_write(
         mut self: Pin<&mut Self>,
-        _cx: &mut task::Context<'_>,
+        cx: &mut task::Context<'_>,
         buf: &[u8],
     ) -> task::Poll<Result<usize, io::Error>> {
+        if self.poll_deadline(cx) {
+            return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
+        }
         let udp_timeout = self.udp_timeout;
         self.timeout
             .as_mut()
             .reset(tokio::time::Instant::now() + udp_timeout);
-        let packet = self.create_rev_packet(TTL, buf.to_vec())?;
-        let payload_len = packet.payload.len();
+        let packet = self.create_rev_packet(self.ttl, self.transform_egress(buf))?;
+        // A transformed datagram no longer maps onto the caller's bytes.
+        let payload_len = match self.transform {
+            Some(_) => buf.len(),
+            None => packet.payload.len(),
+        };
         self.packet_sender
             .send(packet)
             .map_err(|_| Error::from(ErrorKind::UnexpectedEof))?;
@@ -224,3 +360,9 @@ impl AsyncWrite for IpStackUdpStream {
         Poll::Ready(Ok(()))
     }
 }
+
+impl Drop for IpStackUdpStream {
+    fn drop(&mut self) {
+        self.remove(CloseReason::Dropped);
+    }
+}
@@ -0,0 +1,176 @@
+#![allow(dead_code)]
+use etherparse::{PacketBuilder, PacketHeaders, TransportHeader};
+use ipstack::stream::{IpStackStream, IpStackTcpStream};
+use ipstack::{IpStack, IpStackConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+pub const C: [u8; 4] = [10, 0, 0, 2];
+pub const S: [u8; 4] = [1, 1, 1, 1];
+
+pub fn tcp(
+    sport: u16,
+    seq: u32,
+    ack: Option<u32>,
+    flags: &str,
+    win: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut b = PacketBuilder::ipv4(C, S, 64).tcp(sport, 80, seq, win);
+    if let Some(a) = ack {
+        b = b.ack(a);
+    }
+    if flags.contains('S') {
+        b = b.syn();
+    }
+    if flags.contains('F') {
+        b = b.fin();
+    }
+    if flags.contains('R') {
+        b = b.rst();
+    }
+    if flags.contains('P') {
+        b = b.psh();
+    }
+    let mut out = Vec::new();
+    b.write(&mut out, payload).unwrap();
+    out
+}
+
+pub fn udp(sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+    let b = PacketBuilder::ipv4(C, S, 64).udp(sport, dport);
+    let mut out = Vec::new();
+    b.write(&mut out, payload).unwrap();
+    out
+}
+
+pub struct Seg {
+    pub raw: Vec<u8>,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: String,
+    pub win: u16,
+    pub ttl: u8,
+    pub payload: Vec<u8>,
+    pub tcp: Option<etherparse::TcpHeader>,
+}
+
+pub async fn recv(dev: &mut DuplexStream) -> Seg {
+    let raw = tokio::time::timeout(std::time::Duration::from_secs(2), read_one(dev))
+        .await
+        .expect("timeout waiting for egress");
+    let p = PacketHeaders::from_ip_slice(&raw).unwrap();
+    let ttl = match p.ip.as_ref().unwrap() {
+        etherparse::IpHeader::Version4(h, _) => h.time_to_live,
+        etherparse::IpHeader::Version6(h, _) => h.hop_limit,
+    };
+    let payload = p.payload.to_vec();
+    match p.transport {
+        Some(TransportHeader::Tcp(t)) => {
+            let mut flags = String::new();
+            if t.syn {
+                flags.push('S')
+            }
+            if t.ack {
+                flags.push('A')
+            }
+            if t.fin {
+                flags.push('F')
+            }
+            if t.rst {
+                flags.push('R')
+            }
+            if t.psh {
+                flags.push('P')
+            }
+            Seg {
+                raw,
+                seq: t.sequence_number,
+                ack: t.acknowledgment_number,
+                flags,
+                win: t.window_size,
+                ttl,
+                payload,
+                tcp: Some(t),
+            }
+        }
+        _ => Seg {
+            raw,
+            seq: 0,
+            ack: 0,
+            flags: String::new(),
+            win: 0,
+            ttl,
+            payload,
+            tcp: None,
+        },
+    }
+}
+
+pub async fn send(dev: &mut DuplexStream, p: Vec<u8>) {
+    dev.write_all(&p).await.unwrap();
+    // give the stack a chance to read it as a separate frame
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+}
+
+pub async fn read_one(dev: &mut DuplexStream) -> Vec<u8> {
+    let mut head = [0u8; 4];
+    dev.read_exact(&mut head).await.unwrap();
+    let total = if head[0] >> 4 == 4 {
+        u16::from_be_bytes([head[2], head[3]]) as usize
+    } else {
+        let mut more = [0u8; 2];
+        dev.read_exact(&mut more).await.unwrap();
+        let mut v = head.to_vec();
+        v.extend_from_slice(&more);
+        let total = u16::from_be_bytes([more[0], more[1]]) as usize + 40;
+        let mut rest = vec![0u8; total - 6];
+        dev.read_exact(&mut rest).await.unwrap();
+        v.extend_from_slice(&rest);
+        return v;
+    };
+    let mut v = head.to_vec();
+    let mut rest = vec![0u8; total - 4];
+    dev.read_exact(&mut rest).await.unwrap();
+    v.extend_from_slice(&rest);
+    v
+}
+
+pub async fn no_egress(dev: &mut DuplexStream, ms: u64) -> bool {
+    tokio::time::timeout(std::time::Duration::from_millis(ms), read_one(dev))
+        .await
+        .is_err()
+}
+
+pub fn syn_with_options(sport: u16, opts: &[u8]) -> Vec<u8> {
+    let b = PacketBuilder::ipv4(C, S, 64)
+        .tcp(sport, 80, 5000, 65535)
+        .syn()
+        .options_raw(opts)
+        .unwrap();
+    let mut out = Vec::new();
+    b.write(&mut out, &[]).unwrap();
+    out
+}
+
+pub async fn handshake(cfg: IpStackConfig) -> (DuplexStream, IpStackTcpStream, IpStack) {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = IpStack::new(cfg, stack_dev);
+    // MSS 1460
+    send(&mut dev, syn_with_options(1000, &[2, 4, 5, 180])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 0];
+    // drive read to emit SYN-ACK
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let sa = recv(&mut dev).await;
+    assert_eq!(sa.flags, "SA");
+    send(&mut dev, tcp(1000, 5001, Some(sa.seq + 1), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    (dev, t, stack)
+}
+
+pub async fn drive(t: &mut IpStackTcpStream) {
+    let mut b = [0u8; 16];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+}
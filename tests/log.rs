@@ -0,0 +1,80 @@
+mod common;
+
+use common::*;
+use tokio::io::AsyncReadExt;
+
+struct Counter(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+impl tracing::Subscriber for Counter {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, e: &tracing::Event<'_>) {
+        if *e.metadata().level() == tracing::Level::DEBUG {
+            struct V(String);
+            impl tracing::field::Visit for V {
+                fn record_debug(&mut self, f: &tracing::field::Field, v: &dyn std::fmt::Debug) {
+                    self.0 += &format!("{}={:?} ", f.name(), v);
+                }
+            }
+            let mut v = V(String::new());
+            e.record(&mut v);
+            self.0.lock().unwrap().push(v.0);
+        }
+    }
+    fn enter(&self, _: &tracing::span::Id) {}
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn flow_log_level() {
+    use ipstack::stream::IpStackStream;
+    let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    tracing::subscriber::set_global_default(Counter(log.clone())).unwrap();
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.flow_log_level(|tuple| {
+        if tuple.src.port() == 1000 {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::INFO
+        }
+    });
+    let (mut dev, mut t, mut stack) = handshake(cfg).await;
+    send(&mut dev, syn_with_options(2000, &[2, 4, 5, 180])).await;
+    let IpStackStream::Tcp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    drive(&mut u).await;
+    let sa = recv(&mut dev).await;
+    send(&mut dev, tcp(2000, 5001, Some(sa.seq + 1), "", 65535, &[])).await;
+    drive(&mut u).await;
+    let mut b = [0u8; 10];
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"hi")).await;
+    assert!(t.read(&mut b).await.unwrap() > 0);
+    send(&mut dev, tcp(2000, 5001, Some(101), "P", 65535, b"hi")).await;
+    assert!(u.read(&mut b).await.unwrap() > 0);
+    let l = log.lock().unwrap().clone();
+    assert!(
+        l.iter().any(|e| e.contains("flow=tcp 10.0.0.2:1000")),
+        "{l:?}"
+    );
+    assert!(!l.iter().any(|e| e.contains("10.0.0.2:2000")), "{l:?}");
+
+    // levels change per flow afterwards
+    t.set_log_level(tracing::Level::INFO);
+    u.set_log_level(tracing::Level::DEBUG);
+    send(&mut dev, tcp(1000, 5003, Some(101), "P", 65535, b"hi")).await;
+    assert!(t.read(&mut b).await.unwrap() > 0);
+    send(&mut dev, tcp(2000, 5003, Some(101), "P", 65535, b"hi")).await;
+    assert!(u.read(&mut b).await.unwrap() > 0);
+    let l = log.lock().unwrap()[l.len()..].to_vec();
+    assert!(!l.iter().any(|e| e.contains("10.0.0.2:1000")), "{l:?}");
+    assert!(
+        l.iter().any(|e| e.contains("flow=tcp 10.0.0.2:2000")),
+        "{l:?}"
+    );
+}
@@ -0,0 +1,1031 @@
+mod common;
+
+use common::*;
+use ipstack::stream::IpStackStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn shutdown_rst() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.shutdown_rst(true);
+    let (mut dev, _t, s) = handshake(cfg).await;
+    s.shutdown().await;
+    let r = recv(&mut dev).await;
+    assert_eq!(r.flags, "AR");
+    assert_eq!(r.seq, 101);
+    assert_eq!(r.ack, 5001);
+
+    // only established flows are reset: not half-open or closing ones
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.shutdown_rst(true);
+    let mut s = ipstack::IpStack::new(cfg, stack_dev);
+    let mut streams = Vec::new();
+    for port in [1000, 1001, 1002] {
+        send(&mut dev, syn_with_options(port, &[2, 4, 5, 180])).await;
+        let IpStackStream::Tcp(mut t) = s.accept().await.unwrap() else {
+            panic!()
+        };
+        if port != 1001 {
+            drive(&mut t).await;
+            let sa = recv(&mut dev).await;
+            send(&mut dev, tcp(port, 5001, Some(sa.seq + 1), "", 65535, &[])).await;
+            drive(&mut t).await;
+        }
+        streams.push(t);
+    }
+    send(&mut dev, tcp(1002, 5001, Some(101), "F", 65535, &[])).await;
+    drive(&mut streams[2]).await;
+    assert_eq!(recv(&mut dev).await.ack, 5002);
+    s.shutdown().await;
+    let r = recv(&mut dev).await;
+    assert_eq!(r.flags, "AR");
+    assert_eq!(r.tcp.unwrap().destination_port, 1000);
+    let mut rest = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_millis(100),
+        dev.read_to_end(&mut rest),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn jumbo_parse() {
+    // IPv6 header with payload length 0, next header hop-by-hop
+    let payload_len: u32 = 70000;
+    let mut p = vec![0x60, 0, 0, 0, 0, 0, 0, 64];
+    p.extend_from_slice(&[0xfd; 16]);
+    p.extend_from_slice(&[0xfe; 16]);
+    // hop-by-hop: next=17 (udp), len=0 (8 bytes), option c2 len 4 + value
+    let total = payload_len + 8 + 8;
+    p.extend_from_slice(&[17, 0, 0xc2, 4]);
+    p.extend_from_slice(&total.to_be_bytes());
+    // udp header length 0
+    p.extend_from_slice(&[0x10, 0x00, 0x00, 0x35, 0, 0, 0, 1]);
+    p.resize(p.len() + payload_len as usize, 7);
+    p.extend_from_slice(&[9, 9, 9]); // trailing junk
+    let h = etherparse::PacketHeaders::from_ip_slice(&p).unwrap();
+    assert_eq!(h.payload.len(), payload_len as usize + 3);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.read_mtu(100000);
+        let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+        dev.write_all(&p).await.unwrap();
+        let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+            panic!()
+        };
+        let mut b = vec![0u8; 100000];
+        let n = u.read(&mut b).await.unwrap();
+        assert_eq!(n, payload_len as usize);
+    });
+}
+
+#[tokio::test]
+async fn ttl_zero_callback() {
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut cfg = ipstack::IpStackConfig::default();
+    let s2 = seen.clone();
+    cfg.on_ttl_zero(move |t| s2.lock().unwrap().push(t));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let _stack = ipstack::IpStack::new(cfg, stack_dev);
+    let b = etherparse::PacketBuilder::ipv4(C, S, 0).udp(4444, 53);
+    let mut out = Vec::new();
+    b.write(&mut out, b"x").unwrap();
+    send(&mut dev, out).await;
+    let v = seen.lock().unwrap().clone();
+    assert_eq!(v.len(), 1);
+    assert_eq!(v[0].src.port(), 4444);
+    assert_eq!(v[0].dst.port(), 53);
+    assert!(!v[0].tcp);
+}
+
+#[tokio::test]
+async fn local_addr_filter() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.local_addr_filter(|ip| ip == std::net::IpAddr::from([1, 1, 1, 1]));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let b = etherparse::PacketBuilder::ipv4(C, [2, 2, 2, 2], 64).udp(4444, 53);
+    let mut out = Vec::new();
+    b.write(&mut out, b"x").unwrap();
+    send(&mut dev, out).await;
+    send(&mut dev, udp(4445, 53, b"y")).await;
+    let s = stack.accept().await.unwrap();
+    assert_eq!(s.local_addr().port(), 4445);
+}
+
+#[tokio::test]
+async fn close_reasons() {
+    use ipstack::{CloseReason, FlowEvent};
+    // peer RST
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    let mut ev = s.flow_events();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "R", 65535, &[])).await;
+    let mut b = [0u8; 10];
+    assert!(t.read(&mut b).await.is_err());
+    let FlowEvent::Closed(tu, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::PeerReset);
+    assert_eq!(tu.src.port(), 1000);
+    drop(t);
+    // peer FIN
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    let mut ev = s.flow_events();
+    send(&mut dev, tcp(1000, 5001, Some(101), "F", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let a = recv(&mut dev).await;
+    assert_eq!((a.flags.as_str(), a.ack), ("A", 5002));
+    assert!(t.read_closed() && !t.write_closed());
+    assert_eq!(t.read(&mut b).await.unwrap(), 0);
+    t.write_all(b"late").await.unwrap();
+    let d = recv(&mut dev).await;
+    assert_eq!(d.payload, b"late");
+    // shutdown is done once the FIN is out, reading sees the close through
+    t.shutdown().await.unwrap();
+    let fin = recv(&mut dev).await;
+    assert!(fin.flags.contains('F'));
+    assert_eq!(fin.seq, 105);
+    assert!(t.write_closed());
+    send(&mut dev, tcp(1000, 5002, Some(106), "", 65535, &[])).await;
+    let n = tokio::time::timeout(std::time::Duration::from_millis(200), t.read(&mut b))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(n, 0);
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::PeerFin);
+    drop(t);
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(30), ev.recv())
+            .await
+            .is_err()
+    );
+    // dropped
+    let (_dev, t, s) = handshake(Default::default()).await;
+    let mut ev = s.flow_events();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    drop(t);
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::Dropped);
+    // deadline
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.deadline_policy(ipstack::stream::ClosePolicy::Reset);
+    let (_dev, mut t, s) = handshake(cfg).await;
+    let mut ev = s.flow_events();
+    t.set_deadline(tokio::time::Instant::now() + std::time::Duration::from_millis(10));
+    assert!(t.read(&mut b).await.is_err());
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::Deadline);
+    // shutdown
+    let (_dev, _t, s) = handshake(Default::default()).await;
+    let mut ev = s.flow_events();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    s.shutdown().await;
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::Shutdown);
+    // udp idle
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.udp_timeout(std::time::Duration::from_millis(30));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let mut ev = stack.flow_events();
+    send(&mut dev, udp(3333, 53, b"q")).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 100];
+    assert!(u.read(&mut b).await.unwrap() > 0);
+    assert_eq!(u.read(&mut b).await.unwrap(), 0);
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::IdleTimeout);
+    drop(u);
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(30), ev.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn custom_framing() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.frame(|p| {
+        let mut v = vec![0xa5 ^ p[0]];
+        v.extend_from_slice(p);
+        v
+    });
+    cfg.deframe(|b| (b.len() > 1 && b[0] == 0xa5 ^ b[1]).then_some((1, b.len())));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let mut p = udp(3333, 54, b"q");
+    p.insert(0, 0x00); // bad marker
+    send(&mut dev, p).await;
+    let mut p = udp(3334, 54, b"q");
+    p.insert(0, 0xa5 ^ p[0]);
+    send(&mut dev, p).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    assert_eq!(u.local_addr().port(), 3334);
+    u.write_all(b"r").await.unwrap();
+    let mut m = [0u8; 1];
+    dev.read_exact(&mut m).await.unwrap();
+    let r = read_one(&mut dev).await;
+    assert_eq!(m[0], 0xa5 ^ r[0]);
+}
+
+#[tokio::test]
+async fn truncated_ip() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    let mut p = udp(3333, 54, b"q");
+    let l = u16::from_be_bytes([p[2], p[3]]) + 10;
+    p[2..4].copy_from_slice(&l.to_be_bytes());
+    send(&mut dev, p).await;
+    let mut p = udp(3334, 54, b"q");
+    p.extend_from_slice(&[0, 0, 0]);
+    send(&mut dev, p).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    assert_eq!(u.local_addr().port(), 3334);
+    let mut b = [0u8; 10];
+    assert_eq!(u.read(&mut b).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn per_protocol_limits() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.max_tcp_flows(1);
+    cfg.max_udp_flows(1);
+    cfg.udp_overflow_policy(ipstack::stream::UdpOverflowPolicy::PortUnreachable);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, udp(3000, 53, b"a")).await;
+    let _u = stack.accept().await.unwrap();
+    // udp full, tcp still fine
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let _t = stack.accept().await.unwrap();
+    // second udp => port unreachable
+    let orig = udp(3001, 53, b"b");
+    send(&mut dev, orig.clone()).await;
+    let r = read_one(&mut dev).await;
+    let h = etherparse::PacketHeaders::from_ip_slice(&r).unwrap();
+    match h.transport.unwrap() {
+        etherparse::TransportHeader::Icmpv4(i) => {
+            assert_eq!(
+                i.icmp_type,
+                etherparse::Icmpv4Type::DestinationUnreachable(
+                    etherparse::icmpv4::DestUnreachableHeader::Port
+                )
+            );
+            assert_eq!(i.checksum, i.icmp_type.calc_checksum(h.payload));
+        }
+        _ => panic!(),
+    }
+    assert_eq!(h.payload, &orig[..28]);
+    // second tcp => RST
+    send(&mut dev, tcp(1001, 7000, None, "S", 65535, &[])).await;
+    let r = recv(&mut dev).await;
+    assert_eq!(r.flags, "AR");
+    assert_eq!(r.ack, 7001);
+    // a removed flow frees its slot
+    drop(_t);
+    assert_eq!(recv(&mut dev).await.flags, "AR");
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(stack.stats().await.unwrap().tcp_flows, 0);
+    send(&mut dev, tcp(1002, 8000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    assert_eq!(t.local_addr().port(), 1002);
+    assert_eq!(stack.stats().await.unwrap().udp_flows, 1);
+}
+
+struct OneByte(tokio::io::DuplexStream, bool);
+impl tokio::io::AsyncRead for OneByte {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        b: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_read(cx, b)
+    }
+}
+impl tokio::io::AsyncWrite for OneByte {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        b: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.1 = !self.1;
+        if self.1 {
+            return std::task::Poll::Ready(Err(std::io::ErrorKind::Interrupted.into()));
+        }
+        std::pin::Pin::new(&mut self.0).poll_write(cx, &b[..1])
+    }
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn one_byte_device() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), OneByte(stack_dev, false));
+    send(&mut dev, udp(1000, 53, b"q1")).await;
+    send(&mut dev, udp(1001, 53, b"q2")).await;
+    let IpStackStream::Udp(mut a) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let IpStackStream::Udp(mut b) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    a.write_all(b"answer-one").await.unwrap();
+    b.write_all(b"answer-two").await.unwrap();
+    let r1 = recv(&mut dev).await;
+    let r2 = recv(&mut dev).await;
+    assert_eq!(r1.payload, b"answer-one");
+    assert_eq!(r2.payload, b"answer-two");
+}
+
+#[tokio::test]
+async fn pre_accept_buffer() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.pre_accept_buffer(2);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"ab")).await;
+    send(&mut dev, tcp(1000, 5003, Some(101), "P", 65535, b"cd")).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 10];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"ab");
+    assert_eq!(recv(&mut dev).await.flags, "SA");
+    assert_eq!(recv(&mut dev).await.ack, 5003);
+    send(&mut dev, tcp(1000, 5003, Some(101), "P", 65535, b"cd")).await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"cd");
+}
+
+#[tokio::test]
+async fn reap_unused() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.tcp_timeout(std::time::Duration::from_millis(100));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let mut ev = stack.flow_events();
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let r = recv(&mut dev).await;
+    assert_eq!((r.flags.as_str(), r.ack), ("AR", 5001));
+    let ipstack::FlowEvent::Closed(_, reason) = ev.recv().await.unwrap();
+    assert_eq!(reason, ipstack::CloseReason::IdleTimeout);
+    let mut b = [0u8; 4];
+    assert_eq!(
+        t.read(&mut b).await.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionReset
+    );
+    drop(t);
+    assert!(no_egress(&mut dev, 50).await);
+    // a new flow on the same tuple is unaffected
+    send(&mut dev, tcp(1000, 7000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    assert_eq!(recv(&mut dev).await.flags, "SA");
+}
+
+#[tokio::test]
+async fn randomized_ids() {
+    let opts = [1, 1, 8, 10, 0, 0, 0, 7, 0, 0, 0, 0];
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.tcp_timestamps(true);
+    cfg.randomize_flow_ids(true);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let mut res = Vec::new();
+    for port in [1000u16, 1001] {
+        send(&mut dev, syn_with_options(port, &opts)).await;
+        let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+            panic!()
+        };
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut [])).await;
+        let sa = recv(&mut dev).await;
+        let id0 = u16::from_be_bytes([sa.raw[4], sa.raw[5]]);
+        let ts0 = sa
+            .tcp
+            .unwrap()
+            .options_iterator()
+            .find_map(|o| match o {
+                Ok(etherparse::TcpOptionElement::Timestamp(v, _)) => Some(v),
+                _ => None,
+            })
+            .unwrap();
+        send(&mut dev, tcp(port, 5001, Some(101), "P", 65535, b"x")).await;
+        let _ = t.read(&mut [0u8; 4]).await;
+        let a = recv(&mut dev).await;
+        assert_eq!(
+            u16::from_be_bytes([a.raw[4], a.raw[5]]),
+            id0.wrapping_add(1)
+        );
+        res.push((id0, ts0, t));
+    }
+    assert_ne!(res[0].0, res[1].0);
+    assert!(res[0].1.wrapping_sub(res[1].1) > 10_000 || res[1].1.wrapping_sub(res[0].1) > 10_000);
+}
+
+#[tokio::test]
+async fn oversized_frame() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.read_mtu(100);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, udp(1000, 53, &[1u8; 172])).await;
+    assert_eq!(stack.oversized_frames(), 1);
+    send(&mut dev, udp(1001, 53, &[2u8; 72])).await;
+    let IpStackStream::Udp(u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    assert_eq!(u.local_addr().port(), 1001);
+    assert_eq!(stack.oversized_frames(), 1);
+}
+
+#[tokio::test]
+async fn device_eof_stops_stack() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    let mut ev = stack.flow_events();
+    send(&mut dev, udp(1000, 53, b"q")).await;
+    let _u = stack.accept().await.unwrap();
+    drop(dev);
+    let r = tokio::time::timeout(std::time::Duration::from_millis(200), stack.accept())
+        .await
+        .unwrap();
+    assert!(r.is_err());
+    let ipstack::FlowEvent::Closed(_, reason) = ev.recv().await.unwrap();
+    assert_eq!(reason, ipstack::CloseReason::Shutdown);
+}
+
+#[tokio::test]
+async fn on_flow_removed() {
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let s2 = seen.clone();
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.on_flow_removed(move |t, r| s2.lock().unwrap().push((t.src.port(), r)));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, udp(1000, 53, b"a")).await;
+    let u = stack.accept().await.unwrap();
+    drop(u);
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![(1000, ipstack::CloseReason::Dropped)]
+    );
+}
+
+#[tokio::test]
+async fn arena_cap() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.buffer_limit(3000);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let mut flows = Vec::new();
+    for port in [1000u16, 1001] {
+        send(&mut dev, tcp(port, 5000, None, "S", 65535, &[])).await;
+        let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+            panic!()
+        };
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut [])).await;
+        recv(&mut dev).await;
+        send(&mut dev, tcp(port, 5001, Some(101), "", 65535, &[])).await;
+        flows.push(t);
+    }
+    let mut b = [0u8; 10];
+    for i in 1..4u32 {
+        for (k, port) in [1000u16, 1001].iter().enumerate() {
+            send(
+                &mut dev,
+                tcp(*port, 5001 + i * 1000, Some(101), "P", 65535, &[1u8; 1000]),
+            )
+            .await;
+            let _ =
+                tokio::time::timeout(std::time::Duration::from_millis(20), flows[k].read(&mut b))
+                    .await;
+            assert!(stack.buffer_usage() <= 3000, "{}", stack.buffer_usage());
+        }
+    }
+    assert_eq!(stack.buffer_usage(), 3000);
+    send(&mut dev, tcp(2000, 9000, None, "S", 65535, &[])).await;
+    let r = recv(&mut dev).await;
+    assert!(r.flags.contains('R'));
+    // filling the hole releases what was held
+    send(
+        &mut dev,
+        tcp(1000, 5001, Some(101), "P", 65535, &[1u8; 1000]),
+    )
+    .await;
+    let mut big = [0u8; 4000];
+    assert!(flows[0].read(&mut big).await.unwrap() > 0);
+    assert!(stack.buffer_usage() < 3000);
+    drop(flows);
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(stack.buffer_usage(), 0);
+}
+
+#[tokio::test]
+async fn reset_all() {
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    send(&mut dev, udp(1000, 53, b"q")).await;
+    let mut s = s;
+    let u = s.accept().await.unwrap();
+    assert_eq!(s.flow_count().await, 2);
+    s.reset_all().await;
+    assert_eq!(s.flow_count().await, 0);
+    let r = recv(&mut dev).await;
+    assert!(r.flags.contains('R'));
+    let mut b = [0u8; 4];
+    assert!(t.read(&mut b).await.is_err());
+    drop(t);
+    drop(u);
+    assert!(no_egress(&mut dev, 30).await);
+    send(&mut dev, udp(1000, 53, b"q")).await;
+    let _u = s.accept().await.unwrap();
+    assert_eq!(s.flow_count().await, 1);
+}
+
+#[tokio::test]
+async fn bytes_ingress_matches() {
+    let mut results = Vec::new();
+    for enabled in [false, true] {
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.bytes_ingress(enabled);
+        cfg.read_mtu(1500);
+        let (mut dev, mut t, mut s) = handshake(cfg).await;
+        send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"hello")).await;
+        send(
+            &mut dev,
+            tcp(1000, 5006, Some(101), "P", 65535, &[7u8; 1400]),
+        )
+        .await;
+        send(
+            &mut dev,
+            tcp(1000, 5006, Some(101), "P", 65535, &[9u8; 1600]),
+        )
+        .await;
+        send(&mut dev, udp(2000, 53, b"query")).await;
+        let mut got = vec![0u8; 1405];
+        t.read_exact(&mut got).await.unwrap();
+        let IpStackStream::Udp(mut u) = s.accept().await.unwrap() else {
+            panic!()
+        };
+        let mut ub = [0u8; 16];
+        let n = u.read(&mut ub).await.unwrap();
+        got.extend_from_slice(&ub[..n]);
+        results.push((got, s.oversized_frames()));
+    }
+    assert_eq!(results[0], results[1]);
+    assert_eq!(results[1].1, 1);
+}
+
+#[tokio::test]
+async fn dispatch_decisions() {
+    use ipstack::Dispatch;
+    let (mut dev, t, s) = handshake(Default::default()).await;
+    assert_eq!(
+        s.dispatch(&tcp(2000, 1, None, "S", 65535, &[])).await,
+        Dispatch::NewTcpFlow
+    );
+    assert_eq!(s.dispatch(&udp(2000, 53, b"q")).await, Dispatch::NewUdpFlow);
+    assert_eq!(s.dispatch(&[0x45, 1, 2]).await, Dispatch::Drop);
+    assert_eq!(
+        s.dispatch(&tcp(1000, 5001, Some(101), "", 65535, &[]))
+            .await,
+        Dispatch::Existing
+    );
+    assert_eq!(s.flow_count().await, 1);
+    assert!(no_egress(&mut dev, 30).await);
+    drop(t);
+}
+
+#[tokio::test]
+async fn loopback_reflects() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.loopback(true);
+    let s = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, udp(2000, 53, b"ping")).await;
+    let r = recv(&mut dev).await;
+    let p = etherparse::PacketHeaders::from_ip_slice(&r.raw).unwrap();
+    let Some(etherparse::IpHeader::Version4(ip, _)) = p.ip else {
+        panic!()
+    };
+    assert_eq!((ip.source, ip.destination), (S, C));
+    let Some(etherparse::TransportHeader::Udp(u)) = p.transport else {
+        panic!()
+    };
+    assert_eq!((u.source_port, u.destination_port), (53, 2000));
+    assert_eq!(u.checksum, u.calc_checksum_ipv4(&ip, b"ping").unwrap());
+    assert_eq!(ip.header_checksum, ip.calc_header_checksum().unwrap());
+    assert_eq!(r.payload, b"ping");
+    assert_eq!(s.flow_count().await, 0);
+    assert_eq!(
+        s.dispatch(&udp(2000, 53, b"ping")).await,
+        ipstack::Dispatch::Reflect
+    );
+    assert_eq!(
+        s.dispatch(&syn_with_options(1000, &[])).await,
+        ipstack::Dispatch::Reflect
+    );
+}
+#[tokio::test]
+async fn loopback_keeps_ip_options() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.loopback(true);
+    let _s = ipstack::IpStack::new(cfg, stack_dev);
+    // record route with room for two addresses, padded to a word
+    let record_route = [7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut ip = etherparse::Ipv4Header::new(0, 64, 17, C, S);
+    ip.set_options(&record_route).unwrap();
+    let b = etherparse::PacketBuilder::ip(etherparse::IpHeader::Version4(ip, Default::default()))
+        .udp(2000, 53);
+    let mut out = Vec::new();
+    b.write(&mut out, b"ping").unwrap();
+    send(&mut dev, out).await;
+    let r = recv(&mut dev).await;
+    let ihl = (r.raw[0] & 0x0f) as usize * 4;
+    assert_eq!(ihl, 32);
+    assert_eq!(&r.raw[20..32], &record_route);
+    // the header checksum covers the options too
+    let mut sum: u32 = r.raw[..ihl]
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    assert_eq!(sum, 0xffff);
+    assert_eq!(r.payload, b"ping");
+}
+
+#[tokio::test]
+async fn has_flow_query() {
+    let (_dev, t, s) = handshake(Default::default()).await;
+    let tuple = t.network_tuple();
+    assert!(s.has_flow(&tuple).await);
+    let other = ipstack::NetworkTuple::new(tuple.src, "1.1.1.1:81".parse().unwrap(), true);
+    assert!(!s.has_flow(&other).await);
+    assert!(
+        !s.has_flow(&ipstack::NetworkTuple::new(tuple.src, tuple.dst, false))
+            .await
+    );
+}
+
+#[tokio::test]
+async fn reserved_bits_policy() {
+    use ipstack::stream::ReservedBitsPolicy;
+    for policy in [
+        ReservedBitsPolicy::Pass,
+        ReservedBitsPolicy::Ignore,
+        ReservedBitsPolicy::Drop,
+    ] {
+        let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.reserved_bits_policy(policy);
+        let s = ipstack::IpStack::new(cfg, stack_dev);
+        let mut syn = tcp(1000, 5000, None, "S", 65535, &[]);
+        syn[20 + 12] |= 0x02;
+        send(&mut dev, syn).await;
+        let expected = if policy == ReservedBitsPolicy::Drop {
+            0
+        } else {
+            1
+        };
+        assert_eq!(s.flow_count().await, expected, "{policy:?}");
+        // ECN bits aren't reserved
+        let mut syn = tcp(1001, 5000, None, "S", 65535, &[]);
+        syn[20 + 13] |= 0xc0;
+        send(&mut dev, syn).await;
+        assert_eq!(s.flow_count().await, expected + 1, "{policy:?}");
+    }
+}
+
+#[tokio::test]
+async fn wait_ready_after_first_read() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let s = ipstack::IpStack::new(Default::default(), stack_dev);
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(30), s.wait_ready())
+            .await
+            .is_err()
+    );
+    send(&mut dev, udp(2000, 53, b"q")).await;
+    tokio::time::timeout(std::time::Duration::from_millis(100), s.wait_ready())
+        .await
+        .unwrap();
+    s.wait_ready().await;
+    // a stack that stops without reading doesn't hang it
+    let (dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let s = ipstack::IpStack::new(Default::default(), stack_dev);
+    drop(dev);
+    tokio::time::timeout(std::time::Duration::from_millis(100), s.wait_ready())
+        .await
+        .unwrap();
+}
+
+struct FailingWriter;
+impl tokio::io::AsyncWrite for FailingWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        _: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+    }
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn egress_mirror() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let (mirror, mut mirror_rx) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::with_mirror(Default::default(), stack_dev, mirror);
+    send(&mut dev, udp(2000, 53, b"q")).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    for i in 0..5u8 {
+        u.write_all(&[i; 10]).await.unwrap();
+    }
+    let mut primary = Vec::new();
+    let mut copy = Vec::new();
+    for _ in 0..5 {
+        primary.push(recv(&mut dev).await.raw);
+        copy.push(read_one(&mut mirror_rx).await);
+    }
+    assert_eq!(primary, copy);
+
+    // a failing mirror doesn't disturb the device
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::with_mirror(Default::default(), stack_dev, FailingWriter);
+    send(&mut dev, udp(2000, 53, b"q")).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    u.write_all(b"x").await.unwrap();
+    u.write_all(b"y").await.unwrap();
+    assert_eq!(recv(&mut dev).await.payload, b"x");
+    assert_eq!(recv(&mut dev).await.payload, b"y");
+
+    // a stuck mirror doesn't hold up the device; what it can't take is dropped
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let (mirror, _mirror_rx) = tokio::io::duplex(64);
+    let mut stack = ipstack::IpStack::with_mirror(Default::default(), stack_dev, mirror);
+    send(&mut dev, udp(2000, 53, b"q")).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    for i in 0..1100u32 {
+        u.write_all(&i.to_be_bytes()).await.unwrap();
+        assert_eq!(recv(&mut dev).await.payload, i.to_be_bytes());
+    }
+    assert!(stack.mirror_dropped_frames() > 0);
+    assert!(stack.mirror_dropped_frames() < 1100);
+}
+
+#[tokio::test]
+async fn unsolicited_syn_ack_reset() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(2000, 700, Some(12345), "S", 65535, &[])).await;
+    let r = recv(&mut dev).await;
+    assert_eq!(r.flags, "R");
+    assert_eq!(r.seq, 12345);
+    assert_eq!(stack.flow_count().await, 0);
+    assert_eq!(
+        stack
+            .dispatch(&tcp(2000, 700, Some(12345), "S", 65535, &[]))
+            .await,
+        ipstack::Dispatch::Drop
+    );
+    assert_eq!(
+        stack.dispatch(&syn_with_options(2000, &[])).await,
+        ipstack::Dispatch::NewTcpFlow
+    );
+}
+
+#[tokio::test]
+async fn read_watchdog_and_stuck_ingress() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let fired = Arc::new(AtomicUsize::new(0));
+    let f = fired.clone();
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.read_watchdog(std::time::Duration::from_millis(100), move |d| {
+        assert!(d >= std::time::Duration::from_millis(100));
+        f.fetch_add(1, Ordering::SeqCst);
+    });
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    // nothing more is written to the device, so its reads stay pending
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 0);
+    t.write_all(b"out").await.unwrap();
+    let d = recv(&mut dev).await;
+    assert_eq!(d.payload, b"out");
+    tokio::time::sleep(std::time::Duration::from_millis(120)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn accept_many() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    assert!(stack.try_accept_many(8).is_empty());
+    for port in 0..5u16 {
+        send(&mut dev, syn_with_options(3000 + port, &[2, 4, 5, 180])).await;
+    }
+    let first = stack.accept_many(3).await;
+    assert_eq!(first.len(), 3);
+    let rest = stack.try_accept_many(8);
+    assert_eq!(rest.len(), 2);
+    let ports: Vec<u16> = first
+        .iter()
+        .chain(&rest)
+        .map(|s| s.local_addr().port())
+        .collect();
+    assert_eq!(ports, [3000, 3001, 3002, 3003, 3004]);
+    assert!(stack.try_accept_many(8).is_empty());
+    let waiter = tokio::spawn(async move { stack.accept_many(4).await.len() });
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert!(!waiter.is_finished());
+    send(&mut dev, syn_with_options(4000, &[2, 4, 5, 180])).await;
+    assert_eq!(waiter.await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn tcp_data_offset_past_packet() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    let mut p = tcp(1000, 5000, None, "S", 65535, &[]);
+    assert_eq!(p.len(), 40);
+    p[32] = 15 << 4;
+    assert!(matches!(stack.dispatch(&p).await, ipstack::Dispatch::Drop));
+    send(&mut dev, p).await;
+    assert!(no_egress(&mut dev, 50).await);
+    assert_eq!(stack.flow_count().await, 0);
+}
+
+#[tokio::test]
+async fn stats_emitter() {
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    let emitter = s.emit_stats(std::time::Duration::from_millis(50), tx);
+    let first = rx.recv().await.unwrap();
+    assert_eq!(first.tcp_flows, 1);
+    assert_eq!(first.udp_flows, 0);
+    assert_eq!(first.frames_read, 2);
+    assert_eq!(first.packets_sent, 1);
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"hi")).await;
+    let mut b = [0u8; 4];
+    assert!(t.read(&mut b).await.unwrap() > 0);
+    send(&mut dev, udp(7000, 9, b"u")).await;
+    let second = rx.recv().await.unwrap();
+    let third = rx.recv().await.unwrap();
+    assert!(second.frames_read >= first.frames_read);
+    assert!(third.frames_read >= second.frames_read);
+    assert_eq!(third.frames_read, 4);
+    assert_eq!(third.packets_sent, 2);
+    assert_eq!(third.udp_flows, 1);
+    drop(rx);
+    tokio::time::timeout(std::time::Duration::from_millis(200), emitter)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(s.stats().await.unwrap().tcp_flows, 1);
+
+    // a zero interval is clamped, not a panic in the spawned task
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    let emitter = s.emit_stats(std::time::Duration::ZERO, tx);
+    let start = std::time::Instant::now();
+    rx.recv().await.unwrap();
+    rx.recv().await.unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(9));
+    drop(rx);
+    emitter.await.unwrap();
+}
+
+struct WriteFails(tokio::io::DuplexStream);
+impl tokio::io::AsyncRead for WriteFails {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        b: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_read(cx, b)
+    }
+}
+impl tokio::io::AsyncWrite for WriteFails {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        _: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+    }
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn failed_writes_not_counted() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), WriteFails(stack_dev));
+    send(&mut dev, syn_with_options(1000, &[2, 4, 5, 180])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    // the SYN-ACK never makes it to the device
+    drive(&mut t).await;
+    assert_eq!(stack.stats().await.unwrap().packets_sent, 0);
+}
+
+#[tokio::test]
+async fn disable_accept() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.disable_accept(true);
+    cfg.loopback(true);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    let e = tokio::time::timeout(std::time::Duration::from_millis(50), stack.accept())
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+    assert!(matches!(e, ipstack::IpStackError::AcceptDisabled));
+    assert_eq!(
+        e.to_string(),
+        "Accepting streams is disabled by IpStackConfig::disable_accept"
+    );
+    assert!(stack.accept_many(4).await.is_empty());
+    send(&mut dev, syn_with_options(1000, &[2, 4, 5, 180])).await;
+    assert_eq!(recv(&mut dev).await.flags, "S");
+
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.disable_accept(true);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, syn_with_options(1000, &[2, 4, 5, 180])).await;
+    assert_eq!(recv(&mut dev).await.flags, "AR");
+    assert_eq!(stack.flow_count().await, 0);
+    assert!(stack.try_accept_many(4).is_empty());
+    assert!(stack.accept().await.is_err());
+}
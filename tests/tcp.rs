@@ -0,0 +1,1436 @@
+mod common;
+
+use common::*;
+use ipstack::stream::IpStackStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn basic_echo() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"hello")).await;
+    let mut b = [0u8; 100];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"hello");
+    let a = recv(&mut dev).await;
+    assert_eq!(a.ack, 5006);
+    t.write_all(b"world").await.unwrap();
+    let d = recv(&mut dev).await;
+    assert_eq!(d.payload, b"world");
+}
+
+#[tokio::test]
+async fn watermarks() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.mtu(1040);
+    cfg.send_buffer_high_watermark(4000);
+    cfg.send_buffer_low_watermark(1000);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    let data = vec![7u8; 1000];
+    for _ in 0..4 {
+        t.write_all(&data).await.unwrap();
+    }
+    let r = tokio::time::timeout(std::time::Duration::from_millis(50), t.write_all(&data)).await;
+    assert!(r.is_err(), "should block at high watermark");
+    for _ in 0..4 {
+        recv(&mut dev).await;
+    }
+    // ack 2000 bytes -> 2000 unacked > low
+    send(&mut dev, tcp(1000, 5001, Some(2101), "", 65535, &[])).await;
+    let mut b = [0u8; 0];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let r = tokio::time::timeout(std::time::Duration::from_millis(50), t.write(&data)).await;
+    assert!(r.is_err(), "still above low watermark");
+    send(&mut dev, tcp(1000, 5001, Some(3601), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let r = tokio::time::timeout(std::time::Duration::from_millis(50), t.write(&data)).await;
+    assert!(r.is_ok(), "below low watermark");
+}
+
+#[tokio::test]
+async fn keepalive_probe() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    send(&mut dev, tcp(1000, 5000, Some(101), "", 65535, b"x")).await;
+    let mut b = [0u8; 100];
+    let r = tokio::time::timeout(std::time::Duration::from_millis(50), t.read(&mut b)).await;
+    assert!(r.is_err());
+    let a = recv(&mut dev).await;
+    assert_eq!(a.flags, "A");
+    assert_eq!(a.ack, 5001);
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"ok")).await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"ok");
+}
+
+#[tokio::test]
+async fn ooo_cap() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.max_out_of_order_segments(3);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    for i in 0..10u32 {
+        send(
+            &mut dev,
+            tcp(1000, 5001 + 10 + i * 10, Some(101), "", 65535, &[b'a'; 10]),
+        )
+        .await;
+    }
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[b'z'; 10])).await;
+    let mut got = Vec::new();
+    let mut b = [0u8; 100];
+    while let Ok(Ok(n)) =
+        tokio::time::timeout(std::time::Duration::from_millis(50), t.read(&mut b)).await
+    {
+        got.extend_from_slice(&b[..n]);
+    }
+    assert_eq!(got.len(), 40);
+}
+
+#[tokio::test]
+async fn ooo_overlap_bounded() {
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    // 119 overlapping segments past a one-byte hole
+    for i in 0..119u32 {
+        send(
+            &mut dev,
+            tcp(1000, 5002 + i, Some(101), "", 65535, &[i as u8; 8000]),
+        )
+        .await;
+        if i % 16 == 0 {
+            drive(&mut t).await;
+        }
+    }
+    drive(&mut t).await;
+    assert_eq!(t.recv_buffer_len(), 8000 + 118);
+    assert!(t.recv_buffer_len() <= t.recv_buffer_capacity());
+    assert_eq!(s.buffer_usage(), 8000 + 118);
+    assert_eq!(t.debug_tcb().out_of_order_segments, 119);
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, b"h")).await;
+    let mut got = Vec::new();
+    let mut b = [0u8; 4096];
+    while got.len() < 8119 {
+        let n = t.read(&mut b).await.unwrap();
+        got.extend_from_slice(&b[..n]);
+    }
+    let mut expect = b"h".to_vec();
+    expect.extend_from_slice(&[0u8; 8000]);
+    expect.extend((1..119u32).map(|i| i as u8));
+    assert_eq!(got, expect);
+    drive(&mut t).await;
+    assert_eq!(t.recv_buffer_len(), 0);
+    assert_eq!(t.debug_tcb().out_of_order_segments, 0);
+    assert_eq!(s.buffer_usage(), 0);
+
+    // overlapping a held segment on both sides, only the gaps are taken
+    send(
+        &mut dev,
+        tcp(1000, 5001 + 8119 + 4, Some(101), "", 65535, b"HELD"),
+    )
+    .await;
+    send(
+        &mut dev,
+        tcp(1000, 5001 + 8119 + 2, Some(101), "", 65535, b"xxxxxxxxyy"),
+    )
+    .await;
+    send(
+        &mut dev,
+        tcp(1000, 5001 + 8119, Some(101), "", 65535, b"ab"),
+    )
+    .await;
+    let mut got = Vec::new();
+    while got.len() < 12 {
+        let n = t.read(&mut b).await.unwrap();
+        got.extend_from_slice(&b[..n]);
+    }
+    assert_eq!(got, b"abxxHELDxxyy");
+}
+
+#[tokio::test]
+async fn fast_retransmit() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.mtu(1040);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    t.write_all(&[1u8; 1000]).await.unwrap();
+    t.write_all(&[2u8; 1000]).await.unwrap();
+    recv(&mut dev).await;
+    recv(&mut dev).await;
+    let mut b = [0u8; 0];
+    for i in 0..3 {
+        send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+        if i < 2 {
+            assert!(no_egress(&mut dev, 30).await);
+        }
+    }
+    let r = recv(&mut dev).await;
+    assert_eq!(r.seq, 101);
+    assert_eq!(r.payload, vec![1u8; 1000]);
+    assert_eq!(t.retransmit_count(), 1);
+    for _ in 0..3 {
+        send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    }
+    assert_eq!(t.retransmit_count(), 1);
+}
+
+#[tokio::test]
+async fn dup_acks_without_segment() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, syn_with_options(1000, &[2, 4, 5, 180])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    drive(&mut t).await;
+    let sa = recv(&mut dev).await;
+    // the handshake completes on an ACK short of our SYN, so nothing sent
+    // starts where the duplicate ACKs point
+    send(&mut dev, tcp(1000, 5001, Some(sa.seq), "", 65535, &[])).await;
+    drive(&mut t).await;
+    for _ in 0..3 {
+        send(&mut dev, tcp(1000, 5001, Some(sa.seq), "", 65535, &[])).await;
+        drive(&mut t).await;
+    }
+    assert_eq!(t.retransmit_count(), 0);
+    send(
+        &mut dev,
+        tcp(1000, 5001, Some(sa.seq + 1), "P", 65535, b"hi"),
+    )
+    .await;
+    let mut b = [0u8; 4];
+    assert_eq!(t.read(&mut b).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn tap_bytes() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    let (mut inr, mut outr) = t.tap();
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"hello")).await;
+    let mut b = [0u8; 100];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"hello");
+    t.write_all(b"world").await.unwrap();
+    let n = inr.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"hello");
+    let n = outr.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"world");
+    drop(t);
+    assert_eq!(inr.read(&mut b).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn tcp_options_unknown() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    // malformed: unknown kind with length overrunning
+    send(
+        &mut dev,
+        syn_with_options(1001, &[2, 4, 5, 180, 30, 9, 0, 0]),
+    )
+    .await;
+    assert!(no_egress(&mut dev, 50).await);
+    // mss, unknown kind 30 len 4, nop, wscale
+    send(
+        &mut dev,
+        syn_with_options(1002, &[2, 4, 5, 180, 30, 4, 1, 2, 1, 3, 3, 7]),
+    )
+    .await;
+    let s = stack.accept().await.unwrap();
+    assert_eq!(s.peer_addr().port(), 80);
+    assert_eq!(s.local_addr().port(), 1002);
+    // the malformed SYN made no flow
+    let more = tokio::time::timeout(std::time::Duration::from_millis(50), stack.accept()).await;
+    assert!(more.is_err());
+}
+
+#[tokio::test]
+async fn deadline_graceful_and_reset() {
+    for reset in [false, true] {
+        let mut cfg = ipstack::IpStackConfig::default();
+        if reset {
+            cfg.deadline_policy(ipstack::stream::ClosePolicy::Reset);
+        }
+        let (mut dev, mut t, _s) = handshake(cfg).await;
+        t.set_deadline(tokio::time::Instant::now() + std::time::Duration::from_millis(100));
+        let mut seq = 5001;
+        let mut b = [0u8; 100];
+        let start = std::time::Instant::now();
+        let closed = loop {
+            send(&mut dev, tcp(1000, seq, Some(101), "P", 65535, b"data")).await;
+            seq += 4;
+            match tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await {
+                Ok(Err(_)) => break true,
+                Ok(Ok(_)) => {}
+                Err(_) => {}
+            }
+            if start.elapsed() > std::time::Duration::from_millis(300) {
+                break false;
+            }
+        };
+        // drain until FIN or RST
+        let mut saw = String::new();
+        for _ in 0..200 {
+            let r = recv(&mut dev).await;
+            if r.flags.contains('F') || r.flags.contains('R') {
+                saw = r.flags;
+                break;
+            }
+        }
+        if reset {
+            assert!(closed);
+            assert!(saw.contains('R'));
+        } else {
+            assert!(saw.contains('F'));
+            assert!(t.write(b"x").await.is_err());
+        }
+    }
+}
+
+#[tokio::test]
+async fn combined_data_ack_window() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.mtu(1040);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    t.write_all(&[1u8; 1000]).await.unwrap();
+    t.write_all(&[2u8; 1000]).await.unwrap();
+    recv(&mut dev).await;
+    recv(&mut dev).await;
+    // acks the first segment and half the second, carries data, new window
+    send(&mut dev, tcp(1000, 5001, Some(1601), "P", 30000, b"hi")).await;
+    let mut b = [0u8; 100];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"hi");
+    let a = recv(&mut dev).await;
+    assert_eq!(a.ack, 5003);
+    for _ in 0..3 {
+        send(&mut dev, tcp(1000, 5003, Some(1601), "", 30000, &[])).await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    }
+    let r = recv(&mut dev).await;
+    assert_eq!(r.seq, 1601);
+    assert_eq!(r.payload, vec![2u8; 500]);
+}
+
+#[tokio::test]
+async fn peer_window_scaled() {
+    for scaled in [false, true] {
+        let mut cfg = ipstack::IpStackConfig::default();
+        if scaled {
+            cfg.window_scale(2);
+        }
+        let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+        let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+        send(&mut dev, syn_with_options(1000, &[1, 3, 3, 7])).await;
+        let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+            panic!()
+        };
+        let mut b = [0u8; 0];
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+        let sa = recv(&mut dev).await;
+        let opts = sa.tcp.as_ref().unwrap().options().to_vec();
+        if scaled {
+            assert_eq!(opts, vec![1, 3, 3, 2]);
+        } else {
+            assert!(opts.is_empty());
+        }
+        assert_eq!(sa.win, 16384);
+        send(&mut dev, tcp(1000, 5001, Some(101), "", 100, &[])).await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+        assert_eq!(t.peer_window(), if scaled { 12800 } else { 100 });
+        send(&mut dev, tcp(1000, 5001, Some(101), "P", 100, b"x")).await;
+        let mut b1 = [0u8; 10];
+        assert_eq!(t.read(&mut b1).await.unwrap(), 1);
+        let a = recv(&mut dev).await;
+        assert_eq!(a.win as u32, if scaled { 16383 >> 2 } else { 16383 });
+    }
+}
+
+#[tokio::test]
+async fn lost_final_ack() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 0];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    recv(&mut dev).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"early")).await;
+    let mut b = [0u8; 100];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"early");
+    let a = recv(&mut dev).await;
+    assert_eq!(a.ack, 5006);
+}
+
+#[tokio::test]
+async fn established_and_small_reads() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let r = tokio::time::timeout(std::time::Duration::from_millis(30), t.established()).await;
+    assert!(r.is_err());
+    recv(&mut dev).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+    tokio::time::timeout(std::time::Duration::from_millis(30), t.established())
+        .await
+        .unwrap()
+        .unwrap();
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"abcdef")).await;
+    let mut got = Vec::new();
+    let mut b = [0u8; 4];
+    while got.len() < 6 {
+        let n = t.read(&mut b).await.unwrap();
+        got.extend_from_slice(&b[..n]);
+    }
+    assert_eq!(got, b"abcdef");
+}
+
+#[tokio::test]
+async fn window_full_parks_writer() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.mtu(1040);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut _s = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 1500, &[])).await;
+    let IpStackStream::Tcp(mut t) = _s.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b0 = [0u8; 0];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b0)).await;
+    recv(&mut dev).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 1500, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b0)).await;
+    let n1 = t.write(&[1u8; 1000]).await.unwrap();
+    let n2 = t.write(&[1u8; 1000]).await.unwrap();
+    assert_eq!((n1, n2), (1000, 500));
+    recv(&mut dev).await;
+    recv(&mut dev).await;
+    let (mut r, mut w) = tokio::io::split(t);
+    let writer = tokio::spawn(async move {
+        let n = w.write(&[2u8; 100]).await.unwrap();
+        (n, w)
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+    assert!(!writer.is_finished());
+    let reader = tokio::spawn(async move {
+        let mut b = [0u8; 10];
+        let _ = r.read(&mut b).await;
+    });
+    send(&mut dev, tcp(1000, 5001, Some(1601), "", 1500, &[])).await;
+    let (n, _w) = tokio::time::timeout(std::time::Duration::from_millis(100), writer)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(n, 100);
+    reader.abort();
+}
+
+#[tokio::test]
+async fn write_burst_cap() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.mtu(1040);
+    cfg.max_write_segments(3);
+    cfg.send_buffer_high_watermark(1 << 20);
+    cfg.send_buffer_low_watermark(1 << 19);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    let n = t.write(&[5u8; 10000]).await.unwrap();
+    assert_eq!(n, 3000);
+    for i in 0..3 {
+        let r = recv(&mut dev).await;
+        assert_eq!(r.seq, 101 + i * 1000);
+        assert_eq!(r.payload.len(), 1000);
+        assert_eq!(r.flags.contains('P'), i == 2);
+    }
+    assert!(no_egress(&mut dev, 30).await);
+    let n = t.write(&[5u8; 10000]).await.unwrap();
+    assert_eq!(n, 3000);
+}
+#[tokio::test]
+async fn psh_segment_built_once() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.randomize_flow_ids(true);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    t.write_all(b"a").await.unwrap();
+    t.write_all(b"b").await.unwrap();
+    let (a, b) = (recv(&mut dev).await, recv(&mut dev).await);
+    assert_eq!((a.flags.as_str(), b.flags.as_str()), ("AP", "AP"));
+    // each segment takes a single IP ID
+    let id = |s: &Seg| u16::from_be_bytes([s.raw[4], s.raw[5]]);
+    assert_eq!(id(&b), id(&a).wrapping_add(1));
+    let h = etherparse::PacketHeaders::from_ip_slice(&a.raw).unwrap();
+    let etherparse::IpHeader::Version4(ip, _) = h.ip.unwrap() else {
+        panic!()
+    };
+    let tcp = a.tcp.unwrap();
+    assert_eq!(
+        tcp.calc_checksum_ipv4(&ip, &a.payload).unwrap(),
+        tcp.checksum
+    );
+}
+
+#[tokio::test]
+async fn flush_psh() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    t.flush().await.unwrap();
+    assert!(no_egress(&mut dev, 30).await);
+    t.write_all(b"abc").await.unwrap();
+    t.flush().await.unwrap();
+    let r = recv(&mut dev).await;
+    assert_eq!(r.flags, "AP");
+    assert!(no_egress(&mut dev, 30).await);
+}
+
+struct Counting(
+    tokio::io::DuplexStream,
+    std::sync::Arc<std::sync::atomic::AtomicUsize>,
+);
+impl tokio::io::AsyncRead for Counting {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        b: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_read(cx, b)
+    }
+}
+impl tokio::io::AsyncWrite for Counting {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        b: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        std::pin::Pin::new(&mut self.0).poll_write(cx, b)
+    }
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn ack_batching() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.ack_batch_window(std::time::Duration::from_millis(100));
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let writes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut stack = ipstack::IpStack::new(cfg, Counting(stack_dev, writes.clone()));
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 100];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b[..0])).await;
+    let sa = recv(&mut dev).await;
+    assert_eq!(sa.flags, "SA");
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+    let base = writes.load(std::sync::atomic::Ordering::SeqCst);
+    for i in 0..3u32 {
+        send(
+            &mut dev,
+            tcp(1000, 5001 + i * 2, Some(101), "P", 65535, b"hi"),
+        )
+        .await;
+        let n = t.read(&mut b).await.unwrap();
+        assert_eq!(n, 2);
+    }
+    let mut acks = Vec::new();
+    for _ in 0..3 {
+        acks.push(recv(&mut dev).await.ack);
+    }
+    assert_eq!(acks, vec![5003, 5005, 5007]);
+    assert_eq!(writes.load(std::sync::atomic::Ordering::SeqCst) - base, 1);
+    t.write_all(b"x").await.unwrap();
+    let d = recv(&mut dev).await;
+    assert_eq!(d.payload, b"x");
+}
+
+#[tokio::test]
+async fn ack_batch_flushed_on_shutdown() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.ack_batch_window(std::time::Duration::from_secs(10));
+    let (mut dev, mut t, s) = handshake(cfg).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"hi")).await;
+    let mut b = [0u8; 4];
+    assert_eq!(t.read(&mut b).await.unwrap(), 2);
+    assert!(no_egress(&mut dev, 30).await);
+    // only the SYN-ACK is out so far
+    assert_eq!(s.stats().await.unwrap().packets_sent, 1);
+    s.shutdown().await;
+    let a = recv(&mut dev).await;
+    assert_eq!((a.flags.as_str(), a.ack), ("A", 5003));
+}
+
+#[tokio::test]
+async fn challenge_ack() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    send(&mut dev, tcp(1000, 5001, Some(5000), "", 65535, &[])).await;
+    let mut b = [0u8; 100];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let c = recv(&mut dev).await;
+    assert_eq!((c.flags.as_str(), c.seq, c.ack), ("A", 101, 5001));
+    assert_eq!(t.peer_window(), 65535);
+    t.write_all(b"ok").await.unwrap();
+    let d = recv(&mut dev).await;
+    assert_eq!((d.seq, d.payload.as_slice()), (101, &b"ok"[..]));
+    send(&mut dev, tcp(1000, 5001, Some(103), "P", 65535, b"hi")).await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"hi");
+}
+
+#[tokio::test]
+async fn effective_mss_timestamps() {
+    // kind 2 len 4 1460, NOP NOP, kind 8 len 10 ts
+    let opts = [2, 4, 0x05, 0xb4, 1, 1, 8, 10, 0, 0, 0, 7, 0, 0, 0, 0];
+    for ts in [true, false] {
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.mtu(1500);
+        cfg.tcp_timestamps(ts);
+        let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+        let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+        send(&mut dev, syn_with_options(1000, &opts)).await;
+        let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+            panic!()
+        };
+        assert_eq!(t.effective_mss(), if ts { 1448 } else { 1460 });
+        let mut b = [0u8; 0];
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+        let sa = recv(&mut dev).await;
+        let h = sa.tcp.unwrap();
+        let has_ts = h
+            .options_iterator()
+            .any(|o| matches!(o, Ok(etherparse::TcpOptionElement::Timestamp(_, 7))));
+        assert_eq!(has_ts, ts);
+        send(&mut dev, tcp(1000, 5001, Some(sa.seq + 1), "", 65535, &[])).await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+        t.write_all(&vec![1u8; 3000]).await.unwrap();
+        let d = recv(&mut dev).await;
+        assert_eq!(d.payload.len(), if ts { 1448 } else { 1460 });
+        assert!(d.raw.len() <= 1500);
+    }
+}
+
+#[tokio::test]
+async fn shutdown_sends_fin() {
+    use ipstack::stream::TcpState;
+    use std::time::Duration;
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    t.write_all(b"request").await.unwrap();
+    // nothing reads the flow, still the FIN goes out and shutdown returns
+    tokio::time::timeout(Duration::from_millis(50), t.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(recv(&mut dev).await.payload, b"request");
+    let f = recv(&mut dev).await;
+    assert_eq!((f.flags.as_str(), f.seq), ("AF", 108));
+    assert_eq!(t.debug_tcb().state, TcpState::FinWait1);
+    // a second shutdown doesn't send another
+    t.shutdown().await.unwrap();
+    assert!(no_egress(&mut dev, 20).await);
+    // the reply is read after the half-close
+    send(&mut dev, tcp(1000, 5001, Some(109), "P", 65535, b"reply")).await;
+    send(&mut dev, tcp(1000, 5006, Some(109), "F", 65535, &[])).await;
+    let mut out = Vec::new();
+    tokio::time::timeout(Duration::from_millis(200), t.read_to_end(&mut out))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(out, b"reply");
+    assert_eq!(t.debug_tcb().state, TcpState::Closed);
+
+    // from CLOSE_WAIT it goes straight to LAST_ACK
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "F", 65535, &[])).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.ack, 5002);
+    tokio::time::timeout(Duration::from_millis(50), t.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    assert_eq!(t.debug_tcb().state, TcpState::LastAck);
+}
+
+#[tokio::test]
+async fn tcb_snapshot() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    t.write_all(b"abc").await.unwrap();
+    t.write_all(b"defg").await.unwrap();
+    recv(&mut dev).await;
+    recv(&mut dev).await;
+    send(&mut dev, tcp(1000, 5001, Some(104), "P", 65535, b"xy")).await;
+    let mut b = [0u8; 10];
+    assert!(t.read(&mut b).await.unwrap() > 0);
+    let s = t.debug_tcb();
+    assert_eq!(s.state, ipstack::stream::TcpState::Established);
+    assert_eq!((s.snd_una, s.snd_nxt, s.rcv_nxt), (104, 108, 5003));
+    assert!(s.snd_una <= s.snd_nxt);
+    assert_eq!(s.inflight_segments, 1);
+}
+
+#[tokio::test]
+async fn recv_buffer_len() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    assert_eq!(t.recv_buffer_capacity(), 16 * 1024);
+    send(
+        &mut dev,
+        tcp(1000, 5001, Some(101), "P", 65535, b"0123456789"),
+    )
+    .await;
+    let mut b = [0u8; 4];
+    assert_eq!(t.read(&mut b).await.unwrap(), 4);
+    assert_eq!(t.recv_buffer_len(), 6);
+    send(&mut dev, tcp(1000, 5021, Some(101), "P", 65535, b"zz")).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut [])).await;
+    let mut b = [0u8; 10];
+    assert_eq!(t.read(&mut b).await.unwrap(), 6);
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    assert_eq!(t.recv_buffer_len(), 2);
+}
+
+#[tokio::test]
+async fn per_flow_ttl() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, udp(1000, 53, b"a")).await;
+    send(&mut dev, udp(1001, 53, b"b")).await;
+    let IpStackStream::Udp(mut a) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut bs = stack.accept().await.unwrap();
+    assert!(a.set_ttl(0).is_err());
+    a.set_ttl(3).unwrap();
+    a.write_all(b"x").await.unwrap();
+    assert_eq!(recv(&mut dev).await.ttl, 3);
+    let IpStackStream::Udp(ref mut b) = bs else {
+        panic!()
+    };
+    b.write_all(b"y").await.unwrap();
+    assert_eq!(recv(&mut dev).await.ttl, 64);
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    t.set_ttl(7).unwrap();
+    t.write_all(b"z").await.unwrap();
+    assert_eq!(recv(&mut dev).await.ttl, 7);
+}
+
+#[tokio::test]
+async fn write_before_established() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let (w, d) = tokio::join!(t.write_all(b"hello"), async {
+        let sa = recv(&mut dev).await;
+        assert_eq!((sa.flags.as_str(), sa.seq), ("SA", 100));
+        assert!(no_egress(&mut dev, 30).await);
+        send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+        recv(&mut dev).await
+    });
+    w.unwrap();
+    assert_eq!((d.seq, d.payload.as_slice()), (101, &b"hello"[..]));
+}
+
+#[tokio::test]
+async fn data_before_syn_ack() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    // reordered: the second data segment, then the first, then the FIN
+    send(&mut dev, tcp(1000, 5004, Some(101), "P", 65535, b"def")).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"abc")).await;
+    send(&mut dev, tcp(1000, 5007, Some(101), "F", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut out = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_millis(500),
+        t.read_to_end(&mut out),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(out, b"abcdef");
+    assert_eq!(recv(&mut dev).await.flags, "SA");
+}
+
+#[tokio::test]
+async fn accept_contract_fast_data() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"GET /")).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    t.write_all(b"resp").await.unwrap();
+    let mut b = [0u8; 10];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"GET /");
+}
+
+#[tokio::test]
+async fn fin_with_data_waits() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    let mut b = [0u8; 16];
+    send(&mut dev, tcp(1000, 5005, Some(101), "FP", 65535, b"bbbb")).await;
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(30), t.read(&mut b))
+            .await
+            .is_err()
+    );
+    assert!(!t.read_closed());
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"aaaa")).await;
+    let mut got = Vec::new();
+    loop {
+        let n = t.read(&mut b).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        got.extend_from_slice(&b[..n]);
+    }
+    assert_eq!(got, b"aaaabbbb");
+    let mut last = 0;
+    for _ in 0..4 {
+        last = recv(&mut dev).await.ack;
+        if last == 5010 {
+            break;
+        }
+    }
+    assert_eq!(last, 5010);
+    assert!(t.read_closed());
+
+    // FIN past the window is ignored
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    send(
+        &mut dev,
+        tcp(1000, 5001 + 16382, Some(101), "F", 65535, b"cccc"),
+    )
+    .await;
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(30), t.read(&mut b))
+            .await
+            .is_err()
+    );
+    assert!(!t.read_closed());
+    drop(dev);
+}
+
+#[tokio::test]
+async fn persist_probes() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.persist_interval(std::time::Duration::from_millis(20));
+    cfg.persist_max_interval(std::time::Duration::from_millis(40));
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    let mut b = [0u8; 4];
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 0, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(10), t.read(&mut b)).await;
+    let w = tokio::time::timeout(std::time::Duration::from_millis(150), t.write(b"x")).await;
+    assert!(w.is_err());
+    for _ in 0..3 {
+        let p = recv(&mut dev).await;
+        assert_eq!((p.flags.as_str(), p.seq, p.payload.len()), ("A", 100, 0));
+    }
+    assert!(t.probe_count() >= 3);
+    // window opens
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(10), t.read(&mut b)).await;
+    t.write_all(b"x").await.unwrap();
+    assert_eq!(t.probe_count(), 0);
+    loop {
+        let p = recv(&mut dev).await;
+        if !p.payload.is_empty() {
+            assert_eq!(p.payload, b"x");
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn flow_quota() {
+    use ipstack::{stream::ClosePolicy, CloseReason, FlowEvent};
+    for policy in [ClosePolicy::Graceful, ClosePolicy::Reset] {
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.flow_quota(10);
+        cfg.quota_policy(policy);
+        let (mut dev, mut t, s) = handshake(cfg).await;
+        let mut ev = s.flow_events();
+        send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"abcd")).await;
+        let mut b = [0u8; 8];
+        assert_eq!(t.read(&mut b).await.unwrap(), 4);
+        let _ = recv(&mut dev).await; // ack
+        assert_eq!(t.write(b"0123456789").await.unwrap(), 6);
+        assert_eq!(recv(&mut dev).await.payload, b"012345");
+        assert_eq!(t.bytes_transferred(), 10);
+        let r = t.write(b"more").await;
+        let p = recv(&mut dev).await;
+        match policy {
+            ClosePolicy::Graceful => {
+                assert_eq!(r.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+                assert!(p.flags.contains('F'));
+                // the peer sending on past the quota is reset
+                send(&mut dev, tcp(1000, 5005, Some(108), "P", 65535, b"late")).await;
+                let e = tokio::time::timeout(std::time::Duration::from_millis(200), t.read(&mut b))
+                    .await
+                    .unwrap()
+                    .unwrap_err();
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+                assert!(recv(&mut dev).await.flags.contains('R'));
+            }
+            ClosePolicy::Reset => {
+                assert_eq!(r.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+                assert!(p.flags.contains('R'));
+                assert!(t.read(&mut b).await.is_err());
+            }
+        }
+        let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+        assert_eq!(r, CloseReason::QuotaExceeded);
+    }
+
+    // a quota spent during the handshake closes the flow once it's established
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.flow_quota(0);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    let e = t.write(b"x").await.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+}
+
+#[tokio::test]
+async fn default_mss_without_option() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, tcp(1000, 5000, None, "S", 65535, &[])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 0];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let sa = recv(&mut dev).await;
+    send(&mut dev, tcp(1000, 5001, Some(sa.seq + 1), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    assert_eq!(t.effective_mss(), 536);
+    t.write_all(&[3u8; 1200]).await.unwrap();
+    let mut got = Vec::new();
+    for _ in 0..3 {
+        got.push(recv(&mut dev).await.payload.len());
+    }
+    assert_eq!(got, vec![536, 536, 128]);
+}
+
+#[tokio::test]
+async fn timer_jitter_spreads() {
+    let mut firsts = Vec::new();
+    for _ in 0..12 {
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.persist_interval(std::time::Duration::from_millis(20));
+        cfg.timer_jitter(std::time::Duration::from_millis(30));
+        let (mut dev, mut t, _s) = handshake(cfg).await;
+        let mut b = [0u8; 4];
+        send(&mut dev, tcp(1000, 5001, Some(101), "", 0, &[])).await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), t.read(&mut b)).await;
+        let start = std::time::Instant::now();
+        let w = tokio::spawn(async move {
+            let _ =
+                tokio::time::timeout(std::time::Duration::from_millis(200), t.write(b"x")).await;
+        });
+        let p = recv(&mut dev).await;
+        assert_eq!(p.seq, 100);
+        firsts.push(start.elapsed().as_millis());
+        w.abort();
+    }
+    assert!(firsts.iter().all(|&ms| (19..=60).contains(&ms)));
+    assert!(firsts.iter().max().unwrap() - firsts.iter().min().unwrap() >= 8);
+}
+
+#[tokio::test]
+async fn write_deadline_times_out() {
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    let mut b = [0u8; 4];
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 0, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(10), t.read(&mut b)).await;
+    t.set_write_deadline(tokio::time::Instant::now() + std::time::Duration::from_millis(50));
+    let start = std::time::Instant::now();
+    let e = tokio::time::timeout(std::time::Duration::from_millis(500), t.write(b"x"))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(45));
+    // once the window opens, writes go through again
+    send(&mut dev, tcp(1000, 5001, Some(101), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(10), t.read(&mut b)).await;
+    assert_eq!(t.write(b"x").await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn rto_lifecycle() {
+    use std::time::Duration;
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.initial_rto(Duration::from_millis(80));
+    cfg.mtu(1040);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    let mut b = [0u8; 4];
+    assert_eq!(t.debug_tcb().rto_remaining, None);
+    t.write_all(&[1u8; 2000]).await.unwrap();
+    let started = t.debug_tcb().rto_remaining.unwrap();
+    assert!(started > Duration::from_millis(60));
+    recv(&mut dev).await;
+    recv(&mut dev).await;
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    // more data while it runs doesn't restart it
+    t.write_all(&[2u8; 10]).await.unwrap();
+    recv(&mut dev).await;
+    assert!(t.debug_tcb().rto_remaining.unwrap() < Duration::from_millis(45));
+    // an ACK of new data restarts it
+    send(&mut dev, tcp(1000, 5001, Some(1101), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(Duration::from_millis(5), t.read(&mut b)).await;
+    assert!(t.debug_tcb().rto_remaining.unwrap() > Duration::from_millis(60));
+    // an ACK of everything stops it
+    send(&mut dev, tcp(1000, 5001, Some(2111), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(Duration::from_millis(5), t.read(&mut b)).await;
+    assert_eq!(t.debug_tcb().rto_remaining, None);
+    // expiry resends the earliest segment and backs off
+    t.write_all(&[3u8; 10]).await.unwrap();
+    assert_eq!(recv(&mut dev).await.seq, 2111);
+    let _ = tokio::time::timeout(Duration::from_millis(120), t.read(&mut b)).await;
+    let r = recv(&mut dev).await;
+    assert_eq!((r.seq, r.payload.len()), (2111, 10));
+    let snap = t.debug_tcb();
+    assert_eq!(snap.rto, Duration::from_millis(160));
+    assert_eq!(snap.retransmit_count, 1);
+    assert!(snap.rto_remaining.is_some());
+    send(&mut dev, tcp(1000, 5001, Some(2121), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(Duration::from_millis(5), t.read(&mut b)).await;
+    let snap = t.debug_tcb();
+    assert_eq!(
+        (snap.rto, snap.rto_remaining),
+        (Duration::from_millis(80), None)
+    );
+}
+
+#[tokio::test]
+async fn rto_after_fin() {
+    use ipstack::stream::TcpState;
+    use std::time::Duration;
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.initial_rto(Duration::from_millis(80));
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    t.write_all(&[7u8; 4]).await.unwrap();
+    assert_eq!(recv(&mut dev).await.seq, 101);
+    let _ = tokio::time::timeout(Duration::from_millis(5), t.shutdown()).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    assert_eq!(t.debug_tcb().state, TcpState::FinWait1);
+    assert!(t.debug_tcb().rto_remaining.is_some());
+    let mut b = [0u8; 4];
+    let _ = tokio::time::timeout(Duration::from_millis(120), t.read(&mut b)).await;
+    let r = recv(&mut dev).await;
+    assert_eq!((r.seq, r.payload.len()), (101, 4));
+    assert_eq!(t.retransmit_count(), 1);
+}
+
+#[tokio::test]
+async fn initial_recv_window() {
+    for wscale in [false, true] {
+        let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+        let mut cfg = ipstack::IpStackConfig::default();
+        cfg.initial_recv_window(4000);
+        if wscale {
+            cfg.window_scale(4);
+        }
+        let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+        send(
+            &mut dev,
+            syn_with_options(1000, &[2, 4, 5, 180, 1, 3, 3, 2]),
+        )
+        .await;
+        let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+            panic!()
+        };
+        let mut b = [0u8; 0];
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+        let sa = recv(&mut dev).await;
+        assert_eq!(sa.flags, "SA");
+        assert_eq!(sa.win, 4000);
+        send(
+            &mut dev,
+            tcp(1000, 5001, Some(sa.seq + 1), "P", 65535, b"x"),
+        )
+        .await;
+        let mut b = [0u8; 4];
+        assert!(t.read(&mut b).await.unwrap() > 0);
+        let a = recv(&mut dev).await;
+        let expect = (16384u32 - 1) >> if wscale { 4 } else { 0 };
+        assert!(a.win as u32 == expect, "{} {}", a.win, expect);
+    }
+}
+
+#[tokio::test]
+async fn traffic_class_round_trip() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    let ip = etherparse::Ipv6Header {
+        traffic_class: 0xb9,
+        flow_label: 0,
+        payload_length: 0,
+        next_header: 17,
+        hop_limit: 64,
+        source: [0xfd; 16],
+        destination: [0xfe; 16],
+    };
+    let b = etherparse::PacketBuilder::ip(etherparse::IpHeader::Version6(ip, Default::default()))
+        .udp(2000, 9);
+    let mut out = Vec::new();
+    b.write(&mut out, b"q").unwrap();
+    send(&mut dev, out).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut buf = [0u8; 8];
+    assert_eq!(u.read(&mut buf).await.unwrap(), 1);
+    u.write_all(b"r").await.unwrap();
+    // the DSCP is copied, the ECN bits are left Not-ECT
+    let r = recv(&mut dev).await;
+    assert_eq!(r.raw[0], 0x6b);
+    assert_eq!(r.raw[1] >> 4, 0x8);
+    assert_eq!(r.raw[1] & 0x0f, 0);
+    let h = etherparse::Ipv6HeaderSlice::from_slice(&r.raw).unwrap();
+    assert_eq!(h.traffic_class(), 0xb8);
+    u.set_traffic_class(0x2a);
+    u.write_all(b"s").await.unwrap();
+    let r = recv(&mut dev).await;
+    assert_eq!(r.raw[0], 0x62);
+    assert_eq!(r.raw[1] >> 4, 0xa);
+
+    // IPv4: the same byte is the TOS field
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.mtu(1500);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    t.set_traffic_class(0xb9);
+    t.write_all(b"x").await.unwrap();
+    let d = recv(&mut dev).await;
+    assert_eq!(d.raw[1], 0xb9);
+}
+
+#[tokio::test]
+async fn tinygram_coalesce() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.min_segment_size(16);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    for i in 0..10u32 {
+        send(
+            &mut dev,
+            tcp(1000, 5001 + i, Some(101), "P", 65535, &[b'0' + i as u8]),
+        )
+        .await;
+    }
+    let mut b = [0u8; 100];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"0123456789");
+    let a = recv(&mut dev).await;
+    assert_eq!(a.ack, 5011);
+    assert!(no_egress(&mut dev, 50).await);
+    // larger segments are untouched
+    send(
+        &mut dev,
+        tcp(1000, 5011, Some(101), "P", 65535, &[b'x'; 20]),
+    )
+    .await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(n, 20);
+}
+
+#[tokio::test]
+async fn tinygram_drop() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.min_segment_size(16);
+    cfg.tinygram_policy(ipstack::stream::TinygramPolicy::Drop);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    // a lone keystroke is kept
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"k")).await;
+    let mut b = [0u8; 100];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"k");
+    assert_eq!(recv(&mut dev).await.ack, 5002);
+    for i in 0..10u32 {
+        send(
+            &mut dev,
+            tcp(1000, 5002 + i, Some(101), "P", 65535, &[b'0' + i as u8]),
+        )
+        .await;
+    }
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(50), t.read(&mut b))
+            .await
+            .is_err()
+    );
+    assert_eq!(t.tinygrams_dropped(), 9);
+    // the peer resends what was dropped as one segment
+    send(
+        &mut dev,
+        tcp(1000, 5002, Some(101), "P", 65535, b"012345678"),
+    )
+    .await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"012345678");
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"9");
+}
+
+#[tokio::test]
+async fn tinygram_coalesce_behind_hole() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.min_segment_size(16);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    let mut b = [0u8; 100];
+    for i in 1..10u32 {
+        send(
+            &mut dev,
+            tcp(1000, 5001 + i, Some(101), "P", 65535, &[b'0' + i as u8]),
+        )
+        .await;
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(5), t.read(&mut b)).await;
+    }
+    assert_eq!(t.debug_tcb().out_of_order_segments, 1);
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"0")).await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"0123456789");
+}
+
+#[tokio::test]
+async fn wscale_needs_both_syns() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.window_scale(7);
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    assert_eq!(t.debug_tcb().window_scale, None);
+    // peer's window is taken as is
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 1000, b"a")).await;
+    let mut b = [0u8; 4];
+    assert!(t.read(&mut b).await.unwrap() > 0);
+    let a = recv(&mut dev).await;
+    assert_eq!(t.debug_tcb().snd_wnd, 1000);
+    assert_eq!(a.win, 16383);
+}
+
+#[tokio::test]
+async fn handshake_rtt() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(Default::default(), stack_dev);
+    send(&mut dev, syn_with_options(1000, &[2, 4, 5, 180])).await;
+    let IpStackStream::Tcp(mut t) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 0];
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(1), t.read(&mut b)).await;
+    let sa = recv(&mut dev).await;
+    assert_eq!(t.handshake_rtt(), None);
+    tokio::time::sleep(std::time::Duration::from_millis(80)).await;
+    send(&mut dev, tcp(1000, 5001, Some(sa.seq + 1), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.read(&mut b)).await;
+    let rtt = t.handshake_rtt().unwrap();
+    assert!(rtt >= std::time::Duration::from_millis(80), "{:?}", rtt);
+    assert!(rtt < std::time::Duration::from_millis(150), "{:?}", rtt);
+}
+
+#[tokio::test]
+async fn fin_ack_transitions() {
+    use ipstack::stream::TcpState;
+    use ipstack::{CloseReason, FlowEvent};
+    // active close: FIN_WAIT_1 -> FIN_WAIT_2 on a bare ACK, then closed on the peer's FIN
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    let mut ev = s.flow_events();
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(5), t.shutdown()).await;
+    drive(&mut t).await;
+    let f = recv(&mut dev).await;
+    assert_eq!((f.flags.as_str(), f.seq), ("AF", 101));
+    assert_eq!(t.debug_tcb().state, TcpState::FinWait1);
+    assert_eq!(t.debug_tcb().snd_nxt, 102);
+    send(&mut dev, tcp(1000, 5001, Some(102), "", 65535, &[])).await;
+    drive(&mut t).await;
+    assert_eq!(t.debug_tcb().state, TcpState::FinWait2);
+    assert!(!t.read_closed());
+    send(&mut dev, tcp(1000, 5001, Some(102), "F", 65535, &[])).await;
+    drive(&mut t).await;
+    let a = recv(&mut dev).await;
+    assert_eq!((a.flags.as_str(), a.seq, a.ack), ("A", 102, 5002));
+    assert_eq!(t.debug_tcb().state, TcpState::Closed);
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::LocalFin);
+    t.shutdown().await.unwrap();
+
+    // passive close: LAST_ACK -> closed on a bare ACK of our FIN
+    let (mut dev, mut t, s) = handshake(Default::default()).await;
+    let mut ev = s.flow_events();
+    send(&mut dev, tcp(1000, 5001, Some(101), "F", 65535, &[])).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.ack, 5002);
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.shutdown()).await;
+    let f = recv(&mut dev).await;
+    assert_eq!((f.flags.as_str(), f.seq), ("AF", 101));
+    assert_eq!(t.debug_tcb().state, TcpState::LastAck);
+    // an ACK short of our FIN doesn't close it
+    send(&mut dev, tcp(1000, 5002, Some(101), "", 65535, &[])).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(20), t.shutdown()).await;
+    assert_eq!(t.debug_tcb().state, TcpState::LastAck);
+    send(&mut dev, tcp(1000, 5002, Some(102), "", 65535, &[])).await;
+    tokio::time::timeout(std::time::Duration::from_millis(50), t.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(t.debug_tcb().state, TcpState::Closed);
+    let FlowEvent::Closed(_, r) = ev.recv().await.unwrap();
+    assert_eq!(r, CloseReason::PeerFin);
+
+    // simultaneous close: FINs cross, then the ACK of ours closes the flow
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(5), t.shutdown()).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    send(&mut dev, tcp(1000, 5001, Some(101), "F", 65535, &[])).await;
+    drive(&mut t).await;
+    let a = recv(&mut dev).await;
+    assert_eq!((a.flags.as_str(), a.ack), ("A", 5002));
+    assert_eq!(t.debug_tcb().state, TcpState::LastAck);
+    send(&mut dev, tcp(1000, 5002, Some(102), "", 65535, &[])).await;
+    tokio::time::timeout(std::time::Duration::from_millis(50), t.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(t.debug_tcb().state, TcpState::Closed);
+}
+
+#[tokio::test]
+async fn fin_retransmitted() {
+    use ipstack::stream::TcpState;
+    use std::time::Duration;
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.initial_rto(Duration::from_millis(80));
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    t.write_all(&[7u8; 4]).await.unwrap();
+    assert_eq!(recv(&mut dev).await.seq, 101);
+    let _ = tokio::time::timeout(Duration::from_millis(5), t.shutdown()).await;
+    drive(&mut t).await;
+    let f = recv(&mut dev).await;
+    assert_eq!((f.flags.as_str(), f.seq), ("AF", 105));
+    // the data is acknowledged in FIN_WAIT_1, the FIN is lost
+    send(&mut dev, tcp(1000, 5001, Some(105), "", 65535, &[])).await;
+    drive(&mut t).await;
+    let snap = t.debug_tcb();
+    assert_eq!(snap.state, TcpState::FinWait1);
+    assert_eq!(snap.inflight_segments, 0);
+    assert!(snap.rto_remaining.is_some());
+    let mut b = [0u8; 4];
+    let _ = tokio::time::timeout(Duration::from_millis(120), t.read(&mut b)).await;
+    let r = recv(&mut dev).await;
+    assert_eq!((r.flags.as_str(), r.seq), ("AF", 105));
+    assert_eq!(t.retransmit_count(), 1);
+    send(&mut dev, tcp(1000, 5001, Some(106), "", 65535, &[])).await;
+    drive(&mut t).await;
+    let snap = t.debug_tcb();
+    assert_eq!((snap.state, snap.rto_remaining), (TcpState::FinWait2, None));
+
+    // the FIN of a passive close is resent from LAST_ACK too
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.initial_rto(Duration::from_millis(80));
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    send(&mut dev, tcp(1000, 5001, Some(101), "F", 65535, &[])).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.ack, 5002);
+    let _ = tokio::time::timeout(Duration::from_millis(20), t.shutdown()).await;
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    assert_eq!(t.debug_tcb().state, TcpState::LastAck);
+    let _ = tokio::time::timeout(Duration::from_millis(120), t.read(&mut b)).await;
+    let r = recv(&mut dev).await;
+    assert_eq!((r.flags.as_str(), r.seq), ("AF", 101));
+}
+
+#[tokio::test]
+async fn fin_wait_2_data() {
+    use ipstack::stream::TcpState;
+    let (mut dev, mut t, _s) = handshake(Default::default()).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(5), t.shutdown()).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    // data crossing our FIN, in FIN_WAIT_1
+    send(&mut dev, tcp(1000, 5001, Some(101), "P", 65535, b"one")).await;
+    let mut b = [0u8; 16];
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"one");
+    let a = recv(&mut dev).await;
+    assert_eq!((a.flags.as_str(), a.seq, a.ack), ("A", 102, 5004));
+    send(&mut dev, tcp(1000, 5004, Some(102), "", 65535, &[])).await;
+    drive(&mut t).await;
+    assert_eq!(t.debug_tcb().state, TcpState::FinWait2);
+    // out of order, then the hole filled
+    send(&mut dev, tcp(1000, 5007, Some(102), "P", 65535, b"four")).await;
+    send(&mut dev, tcp(1000, 5004, Some(102), "P", 65535, b"two")).await;
+    let mut got = Vec::new();
+    while got.len() < 7 {
+        let n = t.read(&mut b).await.unwrap();
+        got.extend_from_slice(&b[..n]);
+    }
+    assert_eq!(got, b"twofour");
+    let mut last = recv(&mut dev).await;
+    while let Ok(s) =
+        tokio::time::timeout(std::time::Duration::from_millis(20), recv(&mut dev)).await
+    {
+        last = s;
+    }
+    assert_eq!((last.seq, last.ack), (102, 5011));
+    send(&mut dev, tcp(1000, 5011, Some(102), "FP", 65535, b"end")).await;
+    let n = t.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"end");
+    assert_eq!(t.read(&mut b).await.unwrap(), 0);
+    let mut last = recv(&mut dev).await;
+    while let Ok(s) =
+        tokio::time::timeout(std::time::Duration::from_millis(20), recv(&mut dev)).await
+    {
+        last = s;
+    }
+    assert_eq!(last.ack, 5015);
+    assert_eq!(t.debug_tcb().state, TcpState::Closed);
+}
+
+#[tokio::test]
+async fn on_state_change() {
+    use ipstack::stream::TcpState::*;
+    let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut cfg = ipstack::IpStackConfig::default();
+    let l = log.clone();
+    cfg.on_state_change(move |tuple, from, to| {
+        assert_eq!(tuple.src.port(), 1000);
+        l.lock().unwrap().push((from, to))
+    });
+    let (mut dev, mut t, _s) = handshake(cfg).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_millis(5), t.shutdown()).await;
+    drive(&mut t).await;
+    assert_eq!(recv(&mut dev).await.flags, "AF");
+    send(&mut dev, tcp(1000, 5001, Some(102), "", 65535, &[])).await;
+    drive(&mut t).await;
+    send(&mut dev, tcp(1000, 5001, Some(102), "F", 65535, &[])).await;
+    drive(&mut t).await;
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            (SynReceived(true), Established),
+            (Established, FinWait1),
+            (FinWait1, FinWait2),
+            (FinWait2, Closed),
+        ]
+    );
+}
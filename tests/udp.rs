@@ -0,0 +1,95 @@
+mod common;
+
+use common::*;
+use ipstack::stream::IpStackStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn dns_transform() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.dns_transform(|d, p| {
+        let mut v = p.to_vec();
+        v.push(if d == ipstack::stream::UdpDirection::Ingress {
+            b'i'
+        } else {
+            b'e'
+        });
+        v
+    });
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let mut stack = ipstack::IpStack::new(cfg, stack_dev);
+    send(&mut dev, udp(3333, 53, b"q")).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let mut b = [0u8; 100];
+    let n = u.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"qi");
+    u.write_all(b"r").await.unwrap();
+    let p = recv(&mut dev).await;
+    assert_eq!(p.payload, b"re");
+    send(&mut dev, udp(3334, 54, b"q")).await;
+    let IpStackStream::Udp(mut u) = stack.accept().await.unwrap() else {
+        panic!()
+    };
+    let n = u.read(&mut b).await.unwrap();
+    assert_eq!(&b[..n], b"q");
+}
+
+#[tokio::test]
+async fn icmpv6_unreachable() {
+    let mut cfg = ipstack::IpStackConfig::default();
+    cfg.max_udp_flows(0);
+    cfg.udp_overflow_policy(ipstack::stream::UdpOverflowPolicy::PortUnreachable);
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let _stack = ipstack::IpStack::new(cfg, stack_dev);
+    let c6 = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    let s6 = [0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    let mut p = Vec::new();
+    etherparse::PacketBuilder::ipv6(c6, s6, 64)
+        .udp(1000, 53)
+        .write(&mut p, b"hello")
+        .unwrap();
+    send(&mut dev, p.clone()).await;
+    let r = recv(&mut dev).await;
+    let h = etherparse::PacketHeaders::from_ip_slice(&r.raw).unwrap();
+    let Some(etherparse::IpHeader::Version6(ip, _)) = h.ip else {
+        panic!()
+    };
+    assert_eq!((ip.source, ip.destination, ip.next_header), (s6, c6, 58));
+    let Some(etherparse::TransportHeader::Icmpv6(icmp)) = h.transport else {
+        panic!()
+    };
+    assert_eq!(
+        icmp.icmp_type,
+        etherparse::Icmpv6Type::DestinationUnreachable(
+            etherparse::icmpv6::DestUnreachableCode::Port
+        )
+    );
+    assert_eq!(h.payload, &p[..]);
+    assert_eq!(
+        icmp.checksum,
+        icmp.icmp_type.calc_checksum(s6, c6, h.payload).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn udp_zero_checksum() {
+    let (mut dev, stack_dev) = tokio::io::duplex(1 << 20);
+    let s = ipstack::IpStack::new(Default::default(), stack_dev);
+    let mut v4 = udp(2000, 53, b"q");
+    v4[26] = 0;
+    v4[27] = 0;
+    send(&mut dev, v4).await;
+    assert_eq!(s.flow_count().await, 1);
+    let b = etherparse::PacketBuilder::ipv6([0xfd; 16], [0xfe; 16], 64).udp(2000, 53);
+    let mut v6 = Vec::new();
+    b.write(&mut v6, b"q").unwrap();
+    send(&mut dev, v6.clone()).await;
+    assert_eq!(s.flow_count().await, 2);
+    v6[46] = 0;
+    v6[47] = 0;
+    v6[8] = 0xfc; // another source
+    send(&mut dev, v6).await;
+    assert_eq!(s.flow_count().await, 2);
+}